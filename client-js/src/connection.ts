@@ -1,10 +1,17 @@
 import { createConnection, type Socket } from "node:net";
+import { homedir } from "node:os";
+import { join } from "node:path";
 import { FswatchdError } from "./types.js";
 
+/** Daemon socket path or pipe name, resolved the same way as the daemon's default */
 export const SOCKET_PATH =
-  process.platform === "win32"
+  process.env.FSWATCHD_SOCKET ||
+  (process.platform === "win32"
     ? "\\\\.\\pipe\\fswatchd"
-    : "/tmp/fswatchd.sock";
+    : join(
+        process.env.XDG_RUNTIME_DIR || join(homedir(), ".fswatchd"),
+        "fswatchd.sock",
+      ));
 
 /** Check if daemon is reachable */
 export function isConnectable(): Promise<boolean> {
@@ -52,10 +59,14 @@ export type SubscriptionCallback = (paths: string[]) => void;
 export class Client {
   private socket: Socket | null = null;
   private buffer = "";
-  private responseQueue: Array<{
-    resolve: (value: unknown) => void;
-    reject: (error: Error) => void;
-  }> = [];
+  private nextId = 1;
+  private pending = new Map<
+    number,
+    {
+      resolve: (value: unknown) => void;
+      reject: (error: Error) => void;
+    }
+  >();
   private subscriptions = new Map<string, SubscriptionCallback>();
   private connectPromise: Promise<void> | null = null;
   private closed = false;
@@ -82,10 +93,10 @@ export class Client {
         this.connectPromise = null;
         this.socket = null;
         // Reject pending requests
-        for (const { reject } of this.responseQueue) {
+        for (const { reject } of this.pending.values()) {
           reject(new FswatchdError("Connection closed"));
         }
-        this.responseQueue = [];
+        this.pending.clear();
       });
     });
 
@@ -105,21 +116,24 @@ export class Client {
       try {
         const msg = JSON.parse(line) as Record<string, unknown>;
 
-        // If waiting for a response, first line is the response
-        const pending = this.responseQueue[0];
-        if (pending) {
-          this.responseQueue.shift();
-          if ("error" in msg) {
-            pending.reject(new FswatchdError(msg["error"] as string));
-          } else {
-            pending.resolve(msg);
-          }
-        } else if ("key" in msg && "paths" in msg) {
-          // Subscription event (only when not waiting for response)
+        if (msg["type"] === "event") {
           const callback = this.subscriptions.get(msg["key"] as string);
           if (callback) {
             callback(msg["paths"] as string[]);
           }
+          continue;
+        }
+
+        // Responses and errors are matched to their request by ID
+        const id = msg["id"] as number;
+        const pending = this.pending.get(id);
+        if (!pending) continue;
+        this.pending.delete(id);
+
+        if (msg["type"] === "error") {
+          pending.reject(new FswatchdError(msg["error"] as string));
+        } else {
+          pending.resolve(msg);
         }
       } catch {
         // Ignore malformed JSON
@@ -132,12 +146,13 @@ export class Client {
     await this.connect();
 
     return new Promise((resolve, reject) => {
-      this.responseQueue.push({
+      const id = this.nextId++;
+      this.pending.set(id, {
         resolve: resolve as (value: unknown) => void,
         reject,
       });
 
-      const line = JSON.stringify(cmd) + "\n";
+      const line = JSON.stringify({ id, ...cmd }) + "\n";
       this.socket!.write(line);
     });
   }
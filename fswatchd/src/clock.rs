@@ -0,0 +1,112 @@
+//! Logical clocks for `since` queries.
+//!
+//! Every processed batch of watcher events advances the daemon's tick. Each watched
+//! root remembers the tick at which its paths last changed, so a client can ask what
+//! changed after a clock token it received earlier.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+/// Changed paths remembered per root before the oldest half is forgotten
+pub const MAX_HISTORY: usize = 100_000;
+
+/// Format a clock token: `c:<instance>:<tick>`
+pub fn format_clock(instance: &str, tick: u64) -> String {
+    format!("c:{}:{}", instance, tick)
+}
+
+/// Parse a clock token into its daemon instance and tick
+pub fn parse_clock(token: &str) -> Option<(&str, u64)> {
+    let rest = token.strip_prefix("c:")?;
+    let (instance, tick) = rest.rsplit_once(':')?;
+    Some((instance, tick.parse().ok()?))
+}
+
+/// Change history of one watched root
+#[derive(Debug)]
+pub struct RootClock {
+    /// Oldest tick the history can answer from
+    floor: u64,
+    /// Path -> tick of its latest change
+    changes: HashMap<PathBuf, u64>,
+}
+
+impl RootClock {
+    /// History for a root whose watcher started at `tick`
+    pub fn new(tick: u64) -> Self {
+        Self {
+            floor: tick,
+            changes: HashMap::new(),
+        }
+    }
+
+    /// Record a change to a path
+    pub fn record(&mut self, path: PathBuf, tick: u64) {
+        self.changes.insert(path, tick);
+        if self.changes.len() > MAX_HISTORY {
+            let mut ticks: Vec<u64> = self.changes.values().copied().collect();
+            ticks.sort_unstable();
+            self.forget(ticks[ticks.len() / 2]);
+        }
+    }
+
+    /// Forget history up to and including `tick`, e.g. after watcher events were lost
+    pub fn forget(&mut self, tick: u64) {
+        self.floor = self.floor.max(tick);
+        self.changes.retain(|_, changed| *changed > tick);
+    }
+
+    /// Paths changed after `since`, or `None` if the history no longer reaches back that far
+    pub fn changed_since(&self, since: u64) -> Option<Vec<&PathBuf>> {
+        if since < self.floor {
+            return None;
+        }
+        Some(
+            self.changes
+                .iter()
+                .filter(|(_, changed)| **changed > since)
+                .map(|(path, _)| path)
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clock_round_trip() {
+        let token = format_clock("1760000000-42", 7);
+        assert_eq!(token, "c:1760000000-42:7");
+        assert_eq!(parse_clock(&token), Some(("1760000000-42", 7)));
+        assert_eq!(parse_clock("7"), None);
+        assert_eq!(parse_clock("c:x:y"), None);
+    }
+
+    #[test]
+    fn test_changed_since() {
+        let mut clock = RootClock::new(2);
+        clock.record(PathBuf::from("a"), 3);
+        clock.record(PathBuf::from("b"), 4);
+        clock.record(PathBuf::from("a"), 5);
+
+        assert_eq!(clock.changed_since(1), None);
+        let mut changed = clock.changed_since(3).unwrap();
+        changed.sort();
+        assert_eq!(changed, vec![&PathBuf::from("a"), &PathBuf::from("b")]);
+        assert_eq!(clock.changed_since(4).unwrap(), vec![&PathBuf::from("a")]);
+        assert!(clock.changed_since(5).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_forget_raises_floor() {
+        let mut clock = RootClock::new(0);
+        clock.record(PathBuf::from("a"), 1);
+        clock.record(PathBuf::from("b"), 2);
+        clock.forget(1);
+
+        assert_eq!(clock.changed_since(0), None);
+        assert_eq!(clock.changed_since(1).unwrap(), vec![&PathBuf::from("b")]);
+    }
+}
@@ -1,27 +1,80 @@
 //! Daemon-specific logic: watcher management and cache invalidation.
 
 use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
-use std::collections::HashMap;
-use std::path::PathBuf;
-use tokio::sync::mpsc;
+use std::collections::{HashMap, HashSet};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{RwLock, mpsc, watch};
 use tracing::{debug, info};
 
-use crate::hash_service::{self, HashResult};
+use crate::clock::{self, RootClock};
+use crate::hash_service::{FileHashes, HashResult};
 use crate::hasher;
+use crate::persistence;
 
 /// Cache key for glob hash results
 #[derive(Hash, Eq, PartialEq, Clone)]
 pub struct GlobKey {
     pub root: PathBuf,
-    pub path: String,
+    pub path: PathBuf,
     pub glob: String,
 }
 
+/// Hit/miss counters for the daemon caches
+#[derive(Default, Clone, Copy)]
+pub struct CacheStats {
+    pub file_hits: u64,
+    pub file_misses: u64,
+    pub result_hits: u64,
+    pub result_misses: u64,
+}
+
+/// Events a root's watcher dropped, and how many of them were already handled
+struct WatcherDrops {
+    count: Arc<AtomicU64>,
+    seen: u64,
+}
+
+/// Result of a computation shared with identical requests; `None` until it finishes
+type SharedResult = Option<Result<HashResult, hasher::HashError>>;
+type ResultSender = watch::Sender<SharedResult>;
+
+/// What a request for a `GlobKey` has to do
+enum Lookup {
+    Cached(HashResult),
+    /// An identical computation is running; wait for its result
+    Pending(watch::Receiver<SharedResult>),
+    /// Compute and publish the result with `DaemonState::finish`
+    Compute(ResultSender),
+}
+
 /// Daemon state: file cache + result cache + active watchers.
 pub struct DaemonState {
     pub file_cache: HashMap<PathBuf, u64>,
     pub result_cache: HashMap<GlobKey, HashResult>,
     pub root_watchers: HashMap<PathBuf, RecommendedWatcher>,
+    pub stats: CacheStats,
+    /// Watcher events dropped because the event channel was full
+    pub dropped_events: Arc<AtomicU64>,
+    /// Identifies this daemon run in clock tokens
+    instance: String,
+    /// Advances with every processed batch of watcher events
+    tick: u64,
+    /// Change history per watched root
+    clocks: HashMap<PathBuf, RootClock>,
+    /// Events dropped per watched root, to find the roots that missed changes
+    watcher_drops: HashMap<PathBuf, WatcherDrops>,
+    /// Per-root cache generation, bumped by every invalidation under the root
+    generations: HashMap<PathBuf, u64>,
+    /// Results being computed and the root generation they started at, so identical
+    /// concurrent requests compute once
+    in_flight: HashMap<GlobKey, (u64, watch::Receiver<SharedResult>)>,
+    /// Files named by `hash_files` requests in flight, with the number of requests
+    named_files: Arc<Mutex<HashMap<PathBuf, usize>>>,
+    /// Roots whose watcher `hash_files` started; they stay watched so cached file
+    /// hashes remain valid
+    file_list_roots: HashSet<PathBuf>,
 }
 
 impl DaemonState {
@@ -30,12 +83,207 @@ impl DaemonState {
             file_cache: HashMap::new(),
             result_cache: HashMap::new(),
             root_watchers: HashMap::new(),
+            stats: CacheStats::default(),
+            dropped_events: Arc::new(AtomicU64::new(0)),
+            instance: format!("{}-{}", persistence::unix_now(), std::process::id()),
+            tick: 0,
+            clocks: HashMap::new(),
+            watcher_drops: HashMap::new(),
+            generations: HashMap::new(),
+            in_flight: HashMap::new(),
+            named_files: Arc::default(),
+            file_list_roots: HashSet::new(),
+        }
+    }
+
+    /// Name of the watcher backend used for every root
+    pub fn watcher_backend() -> String {
+        format!("{:?}", <RecommendedWatcher as Watcher>::kind()).to_lowercase()
+    }
+
+    /// Whether `hash_files` keeps the root watched
+    pub fn watches_file_lists(&self, root: &Path) -> bool {
+        self.file_list_roots.contains(root)
+    }
+
+    /// Current cache generation of a root
+    fn generation(&mut self, root: &Path) -> u64 {
+        *self.generations.entry(root.to_path_buf()).or_default()
+    }
+
+    /// Mark caches of every root overlapping `path` as changed, so hashes computed
+    /// concurrently are not committed.
+    ///
+    /// Roots containing `path` are skipped if `listed` says their walks never include it
+    /// and no `hash_files` request in flight names it.
+    fn bump_generation(&mut self, path: &Path, listed: impl Fn(&Path) -> bool) {
+        let named = lock(&self.named_files).contains_key(path);
+        for (root, generation) in self.generations.iter_mut() {
+            let overlaps = if path.starts_with(root) {
+                named || listed(root)
+            } else {
+                root.starts_with(path)
+            };
+            if overlaps {
+                *generation += 1;
+            }
+        }
+    }
+
+    /// Combined generation of the roots containing `path`; changes whenever any of them
+    /// is invalidated
+    fn path_generation(&self, path: &Path) -> u64 {
+        self.generations
+            .iter()
+            .filter(|(root, _)| path.starts_with(root))
+            .map(|(_, generation)| *generation)
+            .sum()
+    }
+
+    /// Check the result cache, then computations in flight. On a miss the caller becomes
+    /// the one computing the result.
+    ///
+    /// Only computations started since the root was last invalidated are joined; older
+    /// ones may have read files from before the change.
+    fn lookup(&mut self, key: &GlobKey) -> Lookup {
+        if let Some(result) = self.result_cache.get(key) {
+            debug!(path = %key.path.display(), glob = %key.glob, "cache hit");
+            let result = *result;
+            self.stats.result_hits += 1;
+            return Lookup::Cached(result);
+        }
+
+        // A closed channel means its computation was cancelled; start over
+        let generation = self.generation(&key.root);
+        if let Some((started, rx)) = self.in_flight.get(key)
+            && *started == generation
+            && rx.has_changed().is_ok()
+        {
+            debug!(path = %key.path.display(), glob = %key.glob, "joining computation in flight");
+            self.stats.result_hits += 1;
+            return Lookup::Pending(rx.clone());
+        }
+
+        self.stats.result_misses += 1;
+        let (tx, rx) = watch::channel(None);
+        self.in_flight.insert(key.clone(), (generation, rx));
+        Lookup::Compute(tx)
+    }
+
+    /// Publish the result of a computation started by `lookup` to its waiters
+    fn finish(
+        &mut self,
+        key: &GlobKey,
+        tx: ResultSender,
+        result: &Result<HashResult, hasher::HashError>,
+    ) {
+        if self
+            .in_flight
+            .get(key)
+            .is_some_and(|(_, rx)| rx.same_channel(&tx.subscribe()))
+        {
+            self.in_flight.remove(key);
+        }
+        tx.send_replace(Some(result.clone()));
+    }
+
+    /// Store file hashes and results computed without the lock, unless something
+    /// under the root was invalidated since `generation` was taken.
+    fn commit(
+        &mut self,
+        root: &Path,
+        generation: u64,
+        files: FileHashes,
+        results: Vec<(GlobKey, HashResult)>,
+    ) {
+        self.stats.file_hits += files.hits;
+        self.stats.file_misses += files.misses();
+
+        if self.generation(root) != generation {
+            debug!(root = %root.display(), "discarding hashes invalidated while computing");
+            return;
+        }
+        self.file_cache.extend(files.into_fresh());
+        self.result_cache.extend(results);
+    }
+}
+
+/// Lock a mutex that is never held across a panic
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Counts the files of a `hash_files` request as named for as long as it runs, so
+/// their changes discard its hashes even where walks never list them
+struct NamedFiles {
+    counts: Arc<Mutex<HashMap<PathBuf, usize>>>,
+    paths: Vec<PathBuf>,
+}
+
+impl NamedFiles {
+    fn new(counts: &Arc<Mutex<HashMap<PathBuf, usize>>>, paths: &[PathBuf]) -> Self {
+        let mut named = lock(counts);
+        for path in paths {
+            *named.entry(path.clone()).or_default() += 1;
+        }
+        Self {
+            counts: counts.clone(),
+            paths: paths.to_vec(),
         }
     }
 }
 
+impl Drop for NamedFiles {
+    fn drop(&mut self) {
+        let mut named = lock(&self.counts);
+        for path in &self.paths {
+            if let Some(count) = named.get_mut(path) {
+                *count -= 1;
+                if *count == 0 {
+                    named.remove(path);
+                }
+            }
+        }
+    }
+}
+
+/// Run blocking filesystem work on the blocking thread pool
+pub async fn blocking<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> T {
+    match tokio::task::spawn_blocking(f).await {
+        Ok(value) => value,
+        Err(e) => std::panic::resume_unwind(e.into_panic()),
+    }
+}
+
+/// Wait for a computation in flight. `None` if it was cancelled before finishing.
+async fn wait_for_result(
+    mut rx: watch::Receiver<SharedResult>,
+) -> Option<Result<HashResult, hasher::HashError>> {
+    match rx.wait_for(Option::is_some).await {
+        Ok(result) => result.clone(),
+        Err(_) => None,
+    }
+}
+
+/// Look files up in the file cache under a read lock, then hash the misses without it
+async fn gather_file_hashes(daemon: &RwLock<DaemonState>, files: &[PathBuf]) -> FileHashes {
+    let (mut found, misses) = FileHashes::from_cache(&daemon.read().await.file_cache, files);
+    if misses.is_empty() {
+        return found;
+    }
+    blocking(move || {
+        found.hash_misses(misses);
+        found
+    })
+    .await
+}
+
 /// Invalidates cached hash for a file path.
-pub fn invalidate_file(state: &mut DaemonState, path: &PathBuf) {
+///
+/// `listed` tells whether walks of a root containing the path may include it; if not,
+/// computations in flight under that root are kept.
+pub fn invalidate_file(state: &mut DaemonState, path: &PathBuf, listed: impl Fn(&Path) -> bool) {
+    state.bump_generation(path, listed);
     if state.file_cache.remove(path).is_some() {
         debug!(path = %path.display(), "invalidated file cache");
     }
@@ -50,39 +298,383 @@ pub fn invalidate_file(state: &mut DaemonState, path: &PathBuf) {
 
     for key in keys_to_remove {
         state.result_cache.remove(&key);
-        debug!(path = %key.path, glob = %key.glob, "invalidated result cache");
+        debug!(path = %key.path.display(), glob = %key.glob, "invalidated result cache");
+    }
+}
+
+/// Advances the clock for a processed batch of changed paths and records them on their roots.
+pub fn advance_clock(state: &mut DaemonState, paths: &[&PathBuf]) {
+    state.tick += 1;
+
+    for (root, root_clock) in state.clocks.iter_mut() {
+        for path in paths.iter().filter(|path| path.starts_with(root)) {
+            root_clock.record((*path).clone(), state.tick);
+        }
+    }
+}
+
+/// Whether any watcher dropped events not yet handled by `take_overflowed_roots`
+pub fn has_overflowed(state: &DaemonState) -> bool {
+    state
+        .watcher_drops
+        .values()
+        .any(|drops| drops.count.load(Ordering::Relaxed) != drops.seen)
+}
+
+/// Roots whose watcher dropped events since the last call.
+///
+/// Changes under them may have been missed, so their caches are dropped and their
+/// change history is forgotten.
+pub fn take_overflowed_roots(state: &mut DaemonState) -> Vec<PathBuf> {
+    let roots: Vec<PathBuf> = state
+        .watcher_drops
+        .iter_mut()
+        .filter_map(|(root, drops)| {
+            let count = drops.count.load(Ordering::Relaxed);
+            (std::mem::replace(&mut drops.seen, count) != count).then(|| root.clone())
+        })
+        .collect();
+    if roots.is_empty() {
+        return roots;
+    }
+
+    state.tick += 1;
+    for root in &roots {
+        if let Some(root_clock) = state.clocks.get_mut(root) {
+            root_clock.forget(state.tick);
+        }
+        invalidate_dir(state, root);
+    }
+    roots
+}
+
+/// Current clock token of a watched root
+pub fn clock(state: &DaemonState, root: &Path) -> Option<String> {
+    state
+        .clocks
+        .contains_key(root)
+        .then(|| clock::format_clock(&state.instance, state.tick))
+}
+
+/// Paths under a watched root changed after the given clock token.
+///
+/// Returns `Ok(None)` when the token is from another daemon run or older than the
+/// remembered history, in which case the client has to start fresh.
+pub fn changed_since(
+    state: &DaemonState,
+    root: &Path,
+    token: &str,
+) -> Result<Option<Vec<PathBuf>>, String> {
+    let Some(root_clock) = state.clocks.get(root) else {
+        return Err(format!("Root is not being watched: {}", root.display()));
+    };
+    let Some((instance, tick)) = clock::parse_clock(token) else {
+        return Err(format!("Invalid clock: {}", token));
+    };
+    if instance != state.instance || tick > state.tick {
+        return Ok(None);
+    }
+    Ok(root_clock
+        .changed_since(tick)
+        .map(|paths| paths.into_iter().cloned().collect()))
+}
+
+/// Drops every cached file hash under `dir` and every result that could include such a file.
+///
+/// Returns the number of file entries dropped and the keys of the dropped results.
+pub fn invalidate_dir(state: &mut DaemonState, dir: &Path) -> (usize, Vec<GlobKey>) {
+    state.bump_generation(dir, |_| true);
+    let before = state.file_cache.len();
+    state.file_cache.retain(|path, _| !path.starts_with(dir));
+    let files = before - state.file_cache.len();
+
+    let keys: Vec<GlobKey> = state
+        .result_cache
+        .keys()
+        .filter(|key| {
+            let walk_dir = key.root.join(&key.path);
+            walk_dir.starts_with(dir) || dir.starts_with(&walk_dir)
+        })
+        .cloned()
+        .collect();
+    for key in &keys {
+        state.result_cache.remove(key);
+    }
+
+    info!(dir = %dir.display(), files, results = keys.len(), "invalidated cache");
+    (files, keys)
+}
+
+/// A changed file hashed without the daemon lock, applied with `refresh_file`
+#[derive(Debug, Clone, Copy)]
+pub struct Rehashed {
+    /// Generation of the roots containing the file before it was read
+    generation: u64,
+    /// `None` if the file was deleted, unreadable or not a file
+    hash: Option<u64>,
+}
+
+/// Hashes changed files on the blocking pool, without holding the daemon lock
+pub async fn rehash_files(
+    daemon: &RwLock<DaemonState>,
+    paths: Vec<PathBuf>,
+) -> HashMap<PathBuf, Rehashed> {
+    if paths.is_empty() {
+        return HashMap::new();
     }
+    let generations: Vec<u64> = {
+        let state = daemon.read().await;
+        paths
+            .iter()
+            .map(|path| state.path_generation(path))
+            .collect()
+    };
+    blocking(move || {
+        paths
+            .into_iter()
+            .zip(generations)
+            .map(|(path, generation)| {
+                let hash = hasher::hash_file(&path).ok();
+                (path, Rehashed { generation, hash })
+            })
+            .collect()
+    })
+    .await
+}
+
+/// Applies a file hash from `rehash_files`, invalidating caches only if the content differs.
+///
+/// Returns `false` when the new content hash matches the cached one, in which case
+/// the cached entries are kept. If the file was invalidated since it was read, the hash
+/// may be stale and is not stored.
+pub fn refresh_file(state: &mut DaemonState, path: &PathBuf, rehashed: &Rehashed) -> bool {
+    let current = state.path_generation(path) == rehashed.generation;
+    let Some(new_hash) = rehashed.hash.filter(|_| current) else {
+        invalidate_file(state, path, |_| true);
+        return true;
+    };
+
+    if state.file_cache.get(path) == Some(&new_hash) {
+        debug!(path = %path.display(), "content unchanged");
+        return false;
+    }
+
+    invalidate_file(state, path, |_| true);
+    state.file_cache.insert(path.clone(), new_hash);
+    true
 }
 
 /// Hashes files, optionally starting a watcher for the root directory.
-pub fn hash(
-    state: &mut DaemonState,
+///
+/// The daemon lock is only held to check and update the caches; walking the tree and
+/// reading files happen without it.
+pub async fn hash(
+    daemon: &RwLock<DaemonState>,
     root: &PathBuf,
-    path: &str,
+    path: &Path,
     glob: &str,
     persistent: bool,
     event_tx: Option<mpsc::Sender<Event>>,
 ) -> Result<HashResult, hasher::HashError> {
-    if persistent {
-        start_watching(state, root, event_tx)?;
-    }
-
-    // Check result cache first
     let key = GlobKey {
         root: root.clone(),
-        path: path.to_string(),
+        path: path.to_path_buf(),
         glob: glob.to_string(),
     };
 
-    if let Some(result) = state.result_cache.get(&key) {
-        debug!(path = %path, glob = %glob, "cache hit");
-        return Ok(*result);
+    loop {
+        // Check result cache and computations in flight first
+        let (tx, generation) = {
+            let mut state = daemon.write().await;
+            if persistent {
+                start_watching(&mut state, root, event_tx.clone())?;
+            }
+
+            match state.lookup(&key) {
+                Lookup::Cached(result) => return Ok(result),
+                Lookup::Pending(rx) => {
+                    drop(state);
+                    match wait_for_result(rx).await {
+                        Some(result) => return result,
+                        None => continue,
+                    }
+                }
+                Lookup::Compute(tx) => (tx, state.generation(root)),
+            }
+        };
+
+        // Cache miss - compute without the lock, then store
+        let listed = {
+            let (root, path, glob) = (root.clone(), path.to_path_buf(), glob.to_string());
+            blocking(move || hasher::list_files(&root, &path, &glob)).await
+        };
+        let (result, hashes) = match listed {
+            Ok(files) => {
+                let hashes = gather_file_hashes(daemon, &files).await;
+                (hashes.combine(files), hashes)
+            }
+            Err(e) => (Err(e), FileHashes::default()),
+        };
+
+        let results = match &result {
+            Ok(result) => vec![(key.clone(), *result)],
+            Err(_) => Vec::new(),
+        };
+        let mut state = daemon.write().await;
+        state.commit(root, generation, hashes, results);
+        state.finish(&key, tx, &result);
+        return result;
+    }
+}
+
+/// Hashes a batch of specs, each optionally persistent. Cache misses under the same root
+/// share one directory walk. Returns one result per spec, in order.
+pub async fn hash_many(
+    daemon: &RwLock<DaemonState>,
+    specs: &[(GlobKey, bool)],
+    event_tx: Option<mpsc::Sender<Event>>,
+) -> Vec<Result<HashResult, hasher::HashError>> {
+    let mut results: Vec<Option<Result<HashResult, hasher::HashError>>> =
+        Vec::with_capacity(specs.len());
+    // Root -> (generation, indices of the specs to compute)
+    let mut misses: HashMap<&PathBuf, (u64, Vec<usize>)> = HashMap::new();
+    // Spec index -> sender for the result it computes
+    let mut senders: HashMap<usize, ResultSender> = HashMap::new();
+    let mut pending = Vec::new();
+
+    {
+        let mut state = daemon.write().await;
+        for (i, (key, persistent)) in specs.iter().enumerate() {
+            results.push(None);
+            if *persistent && let Err(e) = start_watching(&mut state, &key.root, event_tx.clone()) {
+                results[i] = Some(Err(e));
+                continue;
+            }
+
+            match state.lookup(key) {
+                Lookup::Cached(result) => results[i] = Some(Ok(result)),
+                Lookup::Pending(rx) => pending.push((i, rx)),
+                Lookup::Compute(tx) => {
+                    let generation = state.generation(&key.root);
+                    misses
+                        .entry(&key.root)
+                        .or_insert_with(|| (generation, Vec::new()))
+                        .1
+                        .push(i);
+                    senders.insert(i, tx);
+                }
+            }
+        }
+    }
+
+    for (root, (generation, indices)) in misses {
+        let root_specs: Vec<(PathBuf, String)> = indices
+            .iter()
+            .map(|&i| (specs[i].0.path.clone(), specs[i].0.glob.clone()))
+            .collect();
+        let listed = {
+            let root = root.clone();
+            blocking(move || {
+                let root_specs: Vec<(&Path, &str)> = root_specs
+                    .iter()
+                    .map(|(path, glob)| (path.as_path(), glob.as_str()))
+                    .collect();
+                hasher::list_files_many(&root, &root_specs)
+            })
+            .await
+        };
+
+        // Hash every uncached file once, even if several specs include it
+        let all_files: Vec<PathBuf> = listed.iter().flatten().flatten().cloned().collect();
+        let hashes = gather_file_hashes(daemon, &all_files).await;
+
+        let mut computed = Vec::new();
+        for (&i, files) in indices.iter().zip(listed) {
+            let result = files.and_then(|files| hashes.combine(files));
+            if let Ok(result) = &result {
+                computed.push((specs[i].0.clone(), *result));
+            }
+            results[i] = Some(result);
+        }
+
+        let mut state = daemon.write().await;
+        state.commit(root, generation, hashes, computed);
+        for i in indices {
+            if let (Some(tx), Some(result)) = (senders.remove(&i), &results[i]) {
+                state.finish(&specs[i].0, tx, result);
+            }
+        }
+    }
+
+    // Wait for identical computations started by other requests
+    for (i, rx) in pending {
+        let result = match wait_for_result(rx).await {
+            Some(result) => result,
+            None => {
+                let (key, _) = &specs[i];
+                hash(daemon, &key.root, &key.path, &key.glob, false, None).await
+            }
+        };
+        results[i] = Some(result);
     }
 
-    // Cache miss - compute and store
-    let result = hash_service::hash_with_cache(&mut state.file_cache, root, path, glob)?;
-    state.result_cache.insert(key, result);
-    Ok(result)
+    results.into_iter().flatten().collect()
+}
+
+/// Whether a path stays below the directory it is joined to: relative and without `..`
+fn stays_inside(path: &Path) -> bool {
+    path.components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+}
+
+/// Hashes files given relative to a root. The root is watched from then on so cached
+/// file hashes stay valid. Returns the combined result and the files that could not be read,
+/// including those outside the root.
+pub async fn hash_files(
+    daemon: &RwLock<DaemonState>,
+    root: &PathBuf,
+    files: &[PathBuf],
+    event_tx: Option<mpsc::Sender<Event>>,
+) -> Result<(HashResult, Vec<(PathBuf, std::io::Error)>), hasher::HashError> {
+    let (inside, outside): (Vec<&PathBuf>, Vec<&PathBuf>) =
+        files.iter().partition(|file| stays_inside(file));
+    let paths: Vec<PathBuf> = inside.iter().map(|file| root.join(file)).collect();
+
+    let (generation, _named) = {
+        let mut state = daemon.write().await;
+        start_watching(&mut state, root, event_tx)?;
+        state.file_list_roots.insert(root.clone());
+        let named = NamedFiles::new(&state.named_files, &paths);
+        (state.generation(root), named)
+    };
+    let mut hashes = gather_file_hashes(daemon, &paths).await;
+    let (result, failed) = hashes.combine_readable(&paths);
+    daemon
+        .write()
+        .await
+        .commit(root, generation, hashes, Vec::new());
+
+    // Report failures with the paths the client sent, in request order
+    let mut failed: Vec<(PathBuf, std::io::Error)> = failed
+        .into_iter()
+        .map(|(path, e)| {
+            let file = inside
+                .iter()
+                .find(|file| root.join(file) == path)
+                .map(|file| file.to_path_buf())
+                .unwrap_or(path);
+            (file, e)
+        })
+        .chain(outside.into_iter().map(|file| {
+            let e = std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Path must be relative to the root without `..`",
+            );
+            (file.clone(), e)
+        }))
+        .collect();
+    failed.sort_by_key(|(file, _)| files.iter().position(|f| f == file));
+    Ok((result, failed))
 }
 
 /// Ensures a watcher is running on a root directory. Public for watch API.
@@ -108,12 +700,18 @@ fn start_watching(
         Some(tx) => tx,
         None => return Ok(()),
     };
+    let dropped_events = state.dropped_events.clone();
+    let root_drops = Arc::new(AtomicU64::new(0));
+    let dropped_here = root_drops.clone();
 
     let mut watcher = notify::recommended_watcher(move |res: Result<Event, notify::Error>| {
         if let Ok(event) = res {
             // Use try_send to avoid blocking - if channel is full, event is dropped
             // This is safer than blocking_send which can have issues from non-tokio threads
-            let _ = tx.try_send(event);
+            if tx.try_send(event).is_err() {
+                dropped_events.fetch_add(1, Ordering::Relaxed);
+                dropped_here.fetch_add(1, Ordering::Relaxed);
+            }
         }
     })
     .map_err(|e| hasher::HashError::Watch(e.to_string()))?;
@@ -124,16 +722,291 @@ fn start_watching(
 
     info!(root = %root.display(), "started watching");
     state.root_watchers.insert(root.clone(), watcher);
+    state
+        .clocks
+        .insert(root.clone(), RootClock::new(state.tick));
+    state.watcher_drops.insert(
+        root.clone(),
+        WatcherDrops {
+            count: root_drops,
+            seen: 0,
+        },
+    );
 
     Ok(())
 }
 
-/// Stops watching a root directory if it exists.
+/// Stops watching a root directory if it exists. Its cached hashes are dropped, since
+/// nothing reports changes under it anymore.
 pub fn stop_watching(state: &mut DaemonState, root: &PathBuf) -> bool {
     if state.root_watchers.remove(root).is_some() {
+        state.clocks.remove(root);
+        state.watcher_drops.remove(root);
+        invalidate_dir(state, root);
         info!(root = %root.display(), "stopped watching");
         true
     } else {
         false
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_commit_discards_invalidated_hashes() {
+        let root = PathBuf::from("/repo");
+        let file = root.join("src/lib.rs");
+        let key = GlobKey {
+            root: root.clone(),
+            path: PathBuf::from("."),
+            glob: "**/*".to_string(),
+        };
+        let computed = || {
+            let (mut hashes, misses) = FileHashes::from_cache(&HashMap::new(), [&file]);
+            assert_eq!(misses, vec![file.clone()]);
+            hashes.hash_misses(Vec::new());
+            let result = HashResult {
+                hash: 1,
+                file_count: 1,
+            };
+            (hashes, vec![(key.clone(), result)])
+        };
+
+        let mut state = DaemonState::new();
+        let generation = state.generation(&root);
+        invalidate_file(&mut state, &file, |_| true);
+        let (hashes, results) = computed();
+        state.commit(&root, generation, hashes, results);
+        assert!(state.result_cache.is_empty());
+
+        let generation = state.generation(&root);
+        let (hashes, results) = computed();
+        state.commit(&root, generation, hashes, results);
+        assert!(state.result_cache.contains_key(&key));
+    }
+
+    #[test]
+    fn test_unlisted_changes_keep_computations_in_flight() {
+        let root = PathBuf::from("/repo");
+        let mut state = DaemonState::new();
+        let generation = state.generation(&root);
+
+        // Walks never list the file, so its change is not a reason to discard hashes
+        invalidate_file(&mut state, &root.join(".git/index.lock"), |_| false);
+        assert_eq!(state.generation(&root), generation);
+
+        // Unless a `hash_files` request in flight names it
+        let env = root.join(".env");
+        let named = NamedFiles::new(&state.named_files, std::slice::from_ref(&env));
+        invalidate_file(&mut state, &env, |_| false);
+        assert_ne!(state.generation(&root), generation);
+
+        drop(named);
+        assert!(lock(&state.named_files).is_empty());
+    }
+
+    #[test]
+    fn test_overflowed_roots_drop_their_caches_and_history() {
+        let root = PathBuf::from("/repo");
+        let other = PathBuf::from("/other");
+        let mut state = DaemonState::new();
+        for dir in [&root, &other] {
+            state.clocks.insert(dir.clone(), RootClock::new(0));
+            state.file_cache.insert(dir.join("a.rs"), 1);
+            state.watcher_drops.insert(
+                dir.clone(),
+                WatcherDrops {
+                    count: Arc::default(),
+                    seen: 0,
+                },
+            );
+        }
+        let token = clock(&state, &root).unwrap();
+        assert!(!has_overflowed(&state));
+
+        state.watcher_drops[&root]
+            .count
+            .fetch_add(3, Ordering::Relaxed);
+        assert!(has_overflowed(&state));
+        assert_eq!(take_overflowed_roots(&mut state), vec![root.clone()]);
+        assert!(take_overflowed_roots(&mut state).is_empty());
+
+        assert!(!state.file_cache.contains_key(&root.join("a.rs")));
+        assert!(state.file_cache.contains_key(&other.join("a.rs")));
+        assert_eq!(changed_since(&state, &root, &token), Ok(None));
+        assert_eq!(changed_since(&state, &other, &token), Ok(Some(Vec::new())));
+    }
+
+    /// Rehash a file and apply the result, as `invalidate_changes` does
+    async fn refresh(daemon: &RwLock<DaemonState>, file: &PathBuf) -> bool {
+        let rehashed = rehash_files(daemon, vec![file.clone()]).await;
+        refresh_file(&mut *daemon.write().await, file, &rehashed[file])
+    }
+
+    #[tokio::test]
+    async fn test_refresh_file_keeps_caches_when_content_unchanged() {
+        let root = std::env::temp_dir().join("fswatchd-test-refresh");
+        let _ = std::fs::remove_dir_all(&root);
+        let _ = std::fs::create_dir_all(&root);
+        let file = root.join("a.rs");
+        let _ = std::fs::write(&file, "a");
+
+        let key = GlobKey {
+            root: root.clone(),
+            path: PathBuf::from("."),
+            glob: "*.rs".to_string(),
+        };
+        let result = HashResult {
+            hash: 1,
+            file_count: 1,
+        };
+        let mut state = DaemonState::new();
+        state
+            .file_cache
+            .insert(file.clone(), hasher::hash_file(&file).unwrap());
+        state.result_cache.insert(key.clone(), result);
+        let daemon = RwLock::new(state);
+
+        // Rewritten with the same content
+        let _ = std::fs::write(&file, "a");
+        assert!(!refresh(&daemon, &file).await);
+        assert!(daemon.read().await.result_cache.contains_key(&key));
+
+        // Changed content replaces the file hash and drops the result
+        let _ = std::fs::write(&file, "b");
+        assert!(refresh(&daemon, &file).await);
+        assert_eq!(
+            daemon.read().await.file_cache.get(&file),
+            Some(&hasher::hash_file(&file).unwrap())
+        );
+        assert!(daemon.read().await.result_cache.is_empty());
+
+        // Deleted
+        let _ = std::fs::remove_file(&file);
+        assert!(refresh(&daemon, &file).await);
+        assert!(!daemon.read().await.file_cache.contains_key(&file));
+
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[tokio::test]
+    async fn test_refresh_file_skips_hash_invalidated_while_reading() {
+        let root = std::env::temp_dir().join("fswatchd-test-refresh-stale");
+        let _ = std::fs::remove_dir_all(&root);
+        let _ = std::fs::create_dir_all(&root);
+        let file = root.join("a.rs");
+        let _ = std::fs::write(&file, "a");
+
+        let daemon = RwLock::new(DaemonState::new());
+        daemon.write().await.generation(&root);
+        let rehashed = rehash_files(&daemon, vec![file.clone()]).await;
+
+        // The root is invalidated after the file was read
+        let mut state = daemon.write().await;
+        invalidate_dir(&mut state, &root);
+        assert!(refresh_file(&mut state, &file, &rehashed[&file]));
+        assert!(!state.file_cache.contains_key(&file));
+        drop(state);
+
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_invalidation_starts_new_computation() {
+        let root = PathBuf::from("/repo");
+        let key = GlobKey {
+            root: root.clone(),
+            path: PathBuf::from("src"),
+            glob: "*.rs".to_string(),
+        };
+        let result = Ok(HashResult {
+            hash: 1,
+            file_count: 1,
+        });
+
+        let mut state = DaemonState::new();
+        let Lookup::Compute(stale) = state.lookup(&key) else {
+            panic!("Expected Compute");
+        };
+        assert!(matches!(state.lookup(&key), Lookup::Pending(_)));
+
+        // A request after the edit must not join the computation started before it
+        invalidate_file(&mut state, &root.join("src/a.rs"), |_| true);
+        let Lookup::Compute(fresh) = state.lookup(&key) else {
+            panic!("Expected Compute after invalidation");
+        };
+        let Lookup::Pending(rx) = state.lookup(&key) else {
+            panic!("Expected Pending");
+        };
+        assert!(rx.same_channel(&fresh.subscribe()));
+
+        // The stale computation finishing leaves the new one in flight
+        state.finish(&key, stale, &result);
+        assert!(state.in_flight.contains_key(&key));
+        state.finish(&key, fresh, &result);
+        assert!(state.in_flight.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_identical_concurrent_hashes_compute_once() {
+        let root = std::env::temp_dir().join("fswatchd-test-single-flight");
+        let _ = std::fs::remove_dir_all(&root);
+        let _ = std::fs::create_dir_all(root.join("src"));
+        let _ = std::fs::write(root.join("src/a.rs"), "a");
+        let _ = std::fs::write(root.join("src/b.rs"), "b");
+
+        let daemon = RwLock::new(DaemonState::new());
+        let (first, second) = tokio::join!(
+            hash(&daemon, &root, Path::new("src"), "*.rs", false, None),
+            hash(&daemon, &root, Path::new("src"), "*.rs", false, None),
+        );
+        assert_eq!(first.unwrap().hash, second.unwrap().hash);
+
+        let state = daemon.read().await;
+        assert_eq!(state.stats.result_misses, 1);
+        assert_eq!(state.stats.file_misses, 2);
+        assert!(state.in_flight.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_hash_files_rejects_paths_outside_root() {
+        let base = std::env::temp_dir().join("fswatchd-test-hash-files-outside");
+        let _ = std::fs::remove_dir_all(&base);
+        let root = base.join("root");
+        let _ = std::fs::create_dir_all(&root);
+        let _ = std::fs::write(root.join("a.rs"), "a");
+        let _ = std::fs::write(root.join("b.rs"), "b");
+        let _ = std::fs::write(base.join("secret"), "s");
+
+        let daemon = RwLock::new(DaemonState::new());
+        let files = [
+            PathBuf::from("../secret"),
+            PathBuf::from("a.rs"),
+            base.join("secret"),
+            PathBuf::from("./b.rs"),
+        ];
+        let (result, failed) = hash_files(&daemon, &root, &files, None).await.unwrap();
+
+        assert_eq!(result.file_count, 2);
+        let failed: Vec<(&PathBuf, std::io::ErrorKind)> =
+            failed.iter().map(|(file, e)| (file, e.kind())).collect();
+        assert_eq!(
+            failed,
+            vec![
+                (&files[0], std::io::ErrorKind::InvalidInput),
+                (&files[2], std::io::ErrorKind::InvalidInput),
+            ]
+        );
+        assert!(
+            !daemon
+                .read()
+                .await
+                .file_cache
+                .contains_key(&base.join("secret"))
+        );
+
+        let _ = std::fs::remove_dir_all(&base);
+    }
+}
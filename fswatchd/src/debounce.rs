@@ -0,0 +1,349 @@
+//! Event debouncing and per-subscription batching.
+//!
+//! This module contains the debounce and batching timing logic, separated from the event loop
+//! to enable unit testing without a real watcher.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::protocol::WatchOptions;
+
+/// Default debounce delay for a changed path
+pub const DEBOUNCE_MS: u64 = 100;
+
+/// Timing settings for a subscription's batches
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BatchSettings {
+    /// Report a path once it has had no events for this long
+    pub debounce: Duration,
+    /// Flush the whole batch at most this long after its first event
+    pub max_delay: Option<Duration>,
+    /// Hold the whole batch until no events arrived for this long
+    pub quiet: Option<Duration>,
+}
+
+impl From<&WatchOptions> for BatchSettings {
+    fn from(options: &WatchOptions) -> Self {
+        Self {
+            debounce: Duration::from_millis(options.debounce_ms.unwrap_or(DEBOUNCE_MS)),
+            max_delay: options.max_delay_ms.map(Duration::from_millis),
+            quiet: options.quiet_ms.map(Duration::from_millis),
+        }
+    }
+}
+
+/// When the events for a path arrived
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EventSpan {
+    pub first: Instant,
+    pub last: Instant,
+}
+
+impl EventSpan {
+    pub fn at(at: Instant) -> Self {
+        Self {
+            first: at,
+            last: at,
+        }
+    }
+}
+
+/// A changed path waiting for cache invalidation
+#[derive(Debug, Clone, Copy)]
+struct PendingPath {
+    span: EventSpan,
+    /// Smallest `max_delay` among the subscriptions matching the path
+    max_delay: Option<Duration>,
+}
+
+/// Changed paths waiting for cache invalidation, shared by all subscriptions.
+///
+/// A path is released once it had no events for the invalidation delay, or once
+/// `max_delay` passed since its first event, so a file that keeps changing is still
+/// reported.
+#[derive(Debug, Default)]
+pub struct Pending {
+    paths: HashMap<PathBuf, PendingPath>,
+}
+
+impl Pending {
+    pub fn len(&self) -> usize {
+        self.paths.len()
+    }
+
+    pub fn contains(&self, path: &Path) -> bool {
+        self.paths.contains_key(path)
+    }
+
+    /// Record an event for a path. `max_delay` only applies when the path is not yet
+    /// pending; later events keep the first-seen time.
+    pub fn add(&mut self, path: PathBuf, at: Instant, max_delay: Option<Duration>) {
+        self.paths
+            .entry(path)
+            .and_modify(|pending| pending.span.last = pending.span.last.max(at))
+            .or_insert(PendingPath {
+                span: EventSpan::at(at),
+                max_delay,
+            });
+    }
+
+    fn deadline(pending: &PendingPath, delay: Duration) -> Instant {
+        let settled = pending.span.last + delay;
+        match pending.max_delay {
+            Some(max_delay) => settled.min(pending.span.first + max_delay),
+            None => settled,
+        }
+    }
+
+    /// Earliest time at which `take_ready` may return paths
+    pub fn next_deadline(&self, delay: Duration) -> Option<Instant> {
+        self.paths
+            .values()
+            .map(|pending| Self::deadline(pending, delay))
+            .min()
+    }
+
+    /// Remove and return the paths that are ready to be invalidated
+    pub fn take_ready(&mut self, now: Instant, delay: Duration) -> Vec<(PathBuf, EventSpan)> {
+        let ready: Vec<(PathBuf, EventSpan)> = self
+            .paths
+            .iter()
+            .filter(|(_, pending)| now >= Self::deadline(pending, delay))
+            .map(|(path, pending)| (path.clone(), pending.span))
+            .collect();
+        for (path, _) in &ready {
+            self.paths.remove(path);
+        }
+        ready
+    }
+
+    /// Remove and return all pending paths
+    pub fn drain(&mut self) -> Vec<(PathBuf, EventSpan)> {
+        self.paths
+            .drain()
+            .map(|(path, pending)| (path, pending.span))
+            .collect()
+    }
+}
+
+/// Changed paths waiting to be reported to one subscription
+#[derive(Debug)]
+pub struct Batch {
+    settings: BatchSettings,
+    /// Path -> time of its latest event
+    paths: HashMap<PathBuf, Instant>,
+    first: Option<Instant>,
+    last: Option<Instant>,
+}
+
+impl Batch {
+    pub fn new(settings: BatchSettings) -> Self {
+        Self {
+            settings,
+            paths: HashMap::new(),
+            first: None,
+            last: None,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.paths.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.paths.len()
+    }
+
+    /// Record a path's events; `max_delay` counts from the first of them
+    pub fn add(&mut self, path: PathBuf, span: EventSpan) {
+        let latest = self.paths.entry(path).or_insert(span.last);
+        *latest = (*latest).max(span.last);
+        self.first = Some(self.first.map_or(span.first, |first| first.min(span.first)));
+        self.last = Some(self.last.map_or(span.last, |last| last.max(span.last)));
+    }
+
+    /// Earliest time at which `take_ready` may return paths
+    pub fn next_deadline(&self) -> Option<Instant> {
+        let (first, last) = (self.first?, self.last?);
+
+        let settled = match self.settings.quiet {
+            Some(quiet) => last + quiet,
+            None => *self.paths.values().min()? + self.settings.debounce,
+        };
+
+        match self.settings.max_delay {
+            Some(max_delay) => Some(settled.min(first + max_delay)),
+            None => Some(settled),
+        }
+    }
+
+    /// Remove and return the paths that are ready to be reported
+    pub fn take_ready(&mut self, now: Instant) -> Vec<PathBuf> {
+        let (Some(first), Some(last)) = (self.first, self.last) else {
+            return Vec::new();
+        };
+
+        let overdue = self
+            .settings
+            .max_delay
+            .is_some_and(|max_delay| now >= first + max_delay);
+
+        let ready: Vec<PathBuf> = if overdue {
+            self.paths.keys().cloned().collect()
+        } else if let Some(quiet) = self.settings.quiet {
+            if now >= last + quiet {
+                self.paths.keys().cloned().collect()
+            } else {
+                Vec::new()
+            }
+        } else {
+            self.paths
+                .iter()
+                .filter(|(_, at)| now >= **at + self.settings.debounce)
+                .map(|(path, _)| path.clone())
+                .collect()
+        };
+
+        for path in &ready {
+            self.paths.remove(path);
+        }
+        self.first = self.paths.values().min().copied();
+        self.last = self.paths.values().max().copied();
+
+        ready
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    fn settings(debounce: u64, max_delay: Option<u64>, quiet: Option<u64>) -> BatchSettings {
+        BatchSettings {
+            debounce: ms(debounce),
+            max_delay: max_delay.map(ms),
+            quiet: quiet.map(ms),
+        }
+    }
+
+    #[test]
+    fn test_settings_from_options() {
+        let defaults = BatchSettings::from(&WatchOptions::default());
+        assert_eq!(defaults, settings(DEBOUNCE_MS, None, None));
+
+        let options = WatchOptions {
+            debounce_ms: Some(0),
+            max_delay_ms: Some(500),
+            quiet_ms: Some(200),
+            ..Default::default()
+        };
+        assert_eq!(
+            BatchSettings::from(&options),
+            settings(0, Some(500), Some(200))
+        );
+    }
+
+    #[test]
+    fn test_debounce_per_path() {
+        let start = Instant::now();
+        let mut batch = Batch::new(settings(100, None, None));
+        batch.add(PathBuf::from("a"), EventSpan::at(start));
+        batch.add(PathBuf::from("b"), EventSpan::at(start + ms(50)));
+
+        assert_eq!(batch.next_deadline(), Some(start + ms(100)));
+        assert!(batch.take_ready(start + ms(99)).is_empty());
+        assert_eq!(batch.take_ready(start + ms(100)), vec![PathBuf::from("a")]);
+        assert_eq!(batch.next_deadline(), Some(start + ms(150)));
+        assert_eq!(batch.take_ready(start + ms(150)), vec![PathBuf::from("b")]);
+        assert!(batch.is_empty());
+        assert_eq!(batch.next_deadline(), None);
+    }
+
+    #[test]
+    fn test_zero_debounce_is_immediate() {
+        let start = Instant::now();
+        let mut batch = Batch::new(settings(0, None, None));
+        batch.add(PathBuf::from("a"), EventSpan::at(start));
+        assert_eq!(batch.take_ready(start), vec![PathBuf::from("a")]);
+    }
+
+    #[test]
+    fn test_quiet_period_waits_for_all_events() {
+        let start = Instant::now();
+        let mut batch = Batch::new(settings(0, None, Some(200)));
+        batch.add(PathBuf::from("a"), EventSpan::at(start));
+        batch.add(PathBuf::from("b"), EventSpan::at(start + ms(150)));
+
+        assert!(batch.take_ready(start + ms(300)).is_empty());
+        assert_eq!(batch.next_deadline(), Some(start + ms(350)));
+
+        let mut ready = batch.take_ready(start + ms(350));
+        ready.sort();
+        assert_eq!(ready, vec![PathBuf::from("a"), PathBuf::from("b")]);
+    }
+
+    #[test]
+    fn test_max_delay_caps_quiet_period() {
+        let start = Instant::now();
+        let mut batch = Batch::new(settings(0, Some(250), Some(200)));
+        batch.add(PathBuf::from("a"), EventSpan::at(start));
+        batch.add(PathBuf::from("b"), EventSpan::at(start + ms(150)));
+
+        assert_eq!(batch.next_deadline(), Some(start + ms(250)));
+        assert_eq!(batch.take_ready(start + ms(250)).len(), 2);
+    }
+
+    #[test]
+    fn test_pending_keeps_first_seen_time() {
+        let start = Instant::now();
+        let mut pending = Pending::default();
+        pending.add(PathBuf::from("a"), start, None);
+        pending.add(PathBuf::from("a"), start + ms(80), Some(ms(10)));
+
+        assert_eq!(pending.next_deadline(ms(100)), Some(start + ms(180)));
+        assert!(pending.take_ready(start + ms(179), ms(100)).is_empty());
+        let span = EventSpan {
+            first: start,
+            last: start + ms(80),
+        };
+        assert_eq!(
+            pending.take_ready(start + ms(180), ms(100)),
+            vec![(PathBuf::from("a"), span)]
+        );
+        assert_eq!(pending.len(), 0);
+    }
+
+    /// Drive `Pending` and `Batch` the way the event loop does, with a file rewritten
+    /// every 50ms for two seconds.
+    #[test]
+    fn test_continuously_changing_path_reported_after_max_delay() {
+        let start = Instant::now();
+        let delay = ms(100);
+        let settings = settings(100, Some(300), None);
+        let mut pending = Pending::default();
+        let mut batch = Batch::new(settings);
+        let mut reported = Vec::new();
+
+        for step in 0..=40 {
+            let now = start + ms(step * 50);
+            pending.add(PathBuf::from("a"), now, settings.max_delay);
+            for (path, span) in pending.take_ready(now, delay) {
+                batch.add(path, span);
+            }
+            if !batch.take_ready(now).is_empty() {
+                reported.push(now - start);
+            }
+        }
+
+        assert_eq!(reported.first(), Some(&ms(300)));
+        assert!(reported.len() >= 5, "reported at {reported:?}");
+        assert!(pending.next_deadline(delay).is_some());
+    }
+}
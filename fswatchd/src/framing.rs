@@ -0,0 +1,261 @@
+//! Message framing on client connections.
+//!
+//! Connections start with NDJSON and may switch to length-prefixed MessagePack with
+//! `hello`. Input is buffered here, so a read can be cancelled by a timeout without
+//! losing a partially received frame.
+
+use std::io;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::protocol::{self, Framing, Message, RequestEnvelope, RequestId};
+
+/// Default size limit of a request line or frame
+pub const DEFAULT_MAX_REQUEST_LEN: usize = 16 * 1024 * 1024;
+
+/// Bytes reserved for each read from the stream
+const READ_CHUNK: usize = 8 * 1024;
+
+/// Splits a client stream into frames
+pub struct FrameReader<R> {
+    reader: R,
+    buf: Vec<u8>,
+    framing: Framing,
+    /// Largest request accepted, in bytes
+    max_len: usize,
+}
+
+impl<R: AsyncRead + Unpin> FrameReader<R> {
+    pub fn new(reader: R, max_len: usize) -> Self {
+        Self {
+            reader,
+            buf: Vec::new(),
+            framing: Framing::Ndjson,
+            max_len,
+        }
+    }
+
+    /// Switch framing; bytes already buffered are read with the new framing
+    pub fn set_framing(&mut self, framing: Framing) {
+        self.framing = framing;
+    }
+
+    /// Next complete frame, or `None` at end of stream. Cancel-safe.
+    ///
+    /// Fails with `InvalidData` once a request exceeds the size limit.
+    pub async fn next_frame(&mut self) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            if let Some(frame) = self.take_frame()? {
+                return Ok(Some(frame));
+            }
+
+            self.buf.reserve(READ_CHUNK);
+            if self.reader.read_buf(&mut self.buf).await? == 0 {
+                // A final NDJSON line may lack its newline
+                if self.framing == Framing::Ndjson && !self.buf.is_empty() {
+                    return Ok(Some(std::mem::take(&mut self.buf)));
+                }
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Remove one complete frame from the buffer, if there is one
+    fn take_frame(&mut self) -> io::Result<Option<Vec<u8>>> {
+        match self.framing {
+            Framing::Ndjson => {
+                let Some(end) = self.buf.iter().position(|&b| b == b'\n') else {
+                    if self.buf.len() > self.max_len {
+                        return Err(too_large(self.max_len));
+                    }
+                    return Ok(None);
+                };
+                if end > self.max_len {
+                    return Err(too_large(self.max_len));
+                }
+                let mut line: Vec<u8> = self.buf.drain(..=end).collect();
+                line.pop();
+                Ok(Some(line))
+            }
+            Framing::Msgpack => {
+                let Some(header) = self.buf.first_chunk::<4>() else {
+                    return Ok(None);
+                };
+                let len = u32::from_be_bytes(*header) as usize;
+                if len > self.max_len {
+                    return Err(too_large(self.max_len));
+                }
+                if self.buf.len() < 4 + len {
+                    return Ok(None);
+                }
+                let frame = self.buf[4..4 + len].to_vec();
+                self.buf.drain(..4 + len);
+                Ok(Some(frame))
+            }
+        }
+    }
+}
+
+fn too_large(max_len: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Request exceeds the {} byte limit", max_len),
+    )
+}
+
+/// Encode a message as one frame
+pub fn encode(
+    framing: Framing,
+    message: &Message,
+) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+    match framing {
+        Framing::Ndjson => {
+            let mut out = serde_json::to_vec(message)?;
+            out.push(b'\n');
+            Ok(out)
+        }
+        Framing::Msgpack => {
+            let body = rmp_serde::to_vec_named(message)?;
+            let len = u32::try_from(body.len())?;
+            let mut out = Vec::with_capacity(4 + body.len());
+            out.extend_from_slice(&len.to_be_bytes());
+            out.extend_from_slice(&body);
+            Ok(out)
+        }
+    }
+}
+
+/// Parse a request frame. On failure, returns the request ID if one could still be read.
+pub fn decode_request(
+    framing: Framing,
+    frame: &[u8],
+) -> Result<RequestEnvelope, (Option<RequestId>, String)> {
+    match framing {
+        Framing::Ndjson => protocol::parse_request(&String::from_utf8_lossy(frame)),
+        Framing::Msgpack => rmp_serde::from_slice::<RequestEnvelope>(frame).map_err(|e| {
+            let id = rmp_serde::from_slice::<serde_json::Value>(frame)
+                .ok()
+                .and_then(|value| value.get("id").cloned())
+                .and_then(|id| serde_json::from_value(id).ok());
+            (id, format!("Invalid request: {}", e))
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{Request, SubscriptionEvent};
+    use std::path::PathBuf;
+
+    fn msgpack_frame(value: &serde_json::Value) -> Vec<u8> {
+        let body = rmp_serde::to_vec_named(value).unwrap();
+        let mut frame = (body.len() as u32).to_be_bytes().to_vec();
+        frame.extend(body);
+        frame
+    }
+
+    #[tokio::test]
+    async fn test_reader_switches_framing() {
+        let mut input = b"{\"cmd\":\"hello\",\"protocol\":1,\"framing\":\"msgpack\"}\n".to_vec();
+        input.extend(msgpack_frame(
+            &serde_json::json!({"id": 3, "cmd": "status"}),
+        ));
+
+        let mut reader = FrameReader::new(input.as_slice(), DEFAULT_MAX_REQUEST_LEN);
+        let hello = reader.next_frame().await.unwrap().unwrap();
+        assert!(matches!(
+            decode_request(Framing::Ndjson, &hello).unwrap().request,
+            Request::Hello {
+                framing: Framing::Msgpack,
+                ..
+            }
+        ));
+
+        reader.set_framing(Framing::Msgpack);
+        let status = reader.next_frame().await.unwrap().unwrap();
+        let envelope = decode_request(Framing::Msgpack, &status).unwrap();
+        assert_eq!(envelope.id, Some(RequestId::Number(3)));
+        assert_eq!(envelope.request, Request::Status);
+        assert_eq!(reader.next_frame().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_reader_rejects_oversized_frame() {
+        let input = 65u32.to_be_bytes();
+        let mut reader = FrameReader::new(&input[..], 64);
+        reader.set_framing(Framing::Msgpack);
+        assert!(reader.next_frame().await.is_err());
+
+        let input = [b'x'; 100];
+        let mut reader = FrameReader::new(&input[..], 64);
+        let error = reader.next_frame().await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        let mut reader = FrameReader::new(&b"{}\n"[..], 64);
+        assert_eq!(reader.next_frame().await.unwrap(), Some(b"{}".to_vec()));
+    }
+
+    #[test]
+    fn test_decode_error_keeps_id() {
+        let frame =
+            rmp_serde::to_vec_named(&serde_json::json!({"id": "a", "cmd": "nope"})).unwrap();
+        let (id, error) = decode_request(Framing::Msgpack, &frame).unwrap_err();
+        assert_eq!(id, Some(RequestId::String("a".to_string())));
+        assert!(error.starts_with("Invalid request"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_msgpack_sends_raw_path_bytes() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = PathBuf::from(OsStr::from_bytes(b"/repo/caf\xe9.rs"));
+        let event = Message::Event(SubscriptionEvent {
+            key: "k".to_string(),
+            paths: vec![path.clone().into()],
+            hash: None,
+            file_count: None,
+            error: None,
+            overflow: false,
+        });
+
+        let frame = encode(Framing::Msgpack, &event).unwrap();
+        let decoded: SubscriptionEvent = rmp_serde::from_slice(&frame[4..]).unwrap();
+        assert_eq!(decoded.paths[0].0, path);
+
+        let line = encode(Framing::Ndjson, &event).unwrap();
+        assert!(String::from_utf8(line).unwrap().contains("caf\u{fffd}.rs"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_msgpack_reads_raw_path_bytes() {
+        use crate::protocol::WirePath;
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        #[derive(serde::Serialize)]
+        struct HashRequest {
+            cmd: &'static str,
+            root: WirePath,
+            path: WirePath,
+            glob: &'static str,
+        }
+
+        let root = PathBuf::from(OsStr::from_bytes(b"/caf\xe9"));
+        let frame = rmp_serde::to_vec_named(&HashRequest {
+            cmd: "hash",
+            root: root.clone().into(),
+            path: "src".into(),
+            glob: "*.rs",
+        })
+        .unwrap();
+
+        match decode_request(Framing::Msgpack, &frame).unwrap().request {
+            Request::Hash { root: decoded, .. } => assert_eq!(decoded.0, root),
+            request => panic!("Expected Hash, got {:?}", request),
+        }
+    }
+}
@@ -1,7 +1,10 @@
 //! Orchestrates file hashing with caching. Reusable across daemon, CLI, or other contexts.
+//!
+//! Nothing here holds a lock: callers look files up in their cache, hash the misses
+//! with `FileHashes::hash_misses` and decide afterwards whether to keep the fresh hashes.
 
-use std::collections::HashMap;
-use std::path::{Path, PathBuf};
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
 
 use crate::hasher;
 
@@ -11,31 +14,138 @@ pub struct HashResult {
     pub file_count: usize,
 }
 
-/// Hashes files matching a glob pattern, using cache for previously hashed files.
-pub fn hash_with_cache(
-    cache: &mut HashMap<PathBuf, u64>,
-    root: &Path,
-    path: &str,
-    glob: &str,
-) -> Result<HashResult, hasher::HashError> {
-    let files = hasher::list_files(root, path, glob)?;
-    let file_count = files.len();
-
-    let mut hashes = Vec::with_capacity(file_count);
-    for file in files {
-        let hash = if let Some(&cached) = cache.get(&file) {
-            cached
-        } else {
-            let h = hasher::hash_file(&file).map_err(|e| hasher::HashError::ReadFile {
-                path: file.clone(),
-                source: e,
-            })?;
-            cache.insert(file, h);
-            h
-        };
-        hashes.push(hash);
+/// Hashes of the files one computation needs
+#[derive(Default)]
+pub struct FileHashes {
+    hashes: HashMap<PathBuf, u64>,
+    /// Hashed for this computation and not yet in the cache
+    fresh: Vec<(PathBuf, u64)>,
+    failed: HashMap<PathBuf, std::io::Error>,
+    /// Files found in the cache
+    pub hits: u64,
+}
+
+impl FileHashes {
+    /// Looks files up in a cache. Returns the cached hashes and the files still to hash.
+    pub fn from_cache<'a>(
+        cache: &HashMap<PathBuf, u64>,
+        files: impl IntoIterator<Item = &'a PathBuf>,
+    ) -> (Self, Vec<PathBuf>) {
+        let mut found = Self::default();
+        let mut misses = HashSet::new();
+        for file in files {
+            match cache.get(file) {
+                Some(&h) => {
+                    if found.hashes.insert(file.clone(), h).is_none() {
+                        found.hits += 1;
+                    }
+                }
+                None => {
+                    misses.insert(file.clone());
+                }
+            }
+        }
+        (found, misses.into_iter().collect())
+    }
+
+    /// Hashes files on all available cores.
+    pub fn hash_misses(&mut self, misses: Vec<PathBuf>) {
+        for (file, result) in hash_files_parallel(misses) {
+            match result {
+                Ok(h) => {
+                    self.hashes.insert(file.clone(), h);
+                    self.fresh.push((file, h));
+                }
+                Err(e) => {
+                    self.failed.insert(file, e);
+                }
+            }
+        }
+    }
+
+    /// Hashes computed for this computation, to be added to the cache
+    pub fn into_fresh(self) -> Vec<(PathBuf, u64)> {
+        self.fresh
+    }
+
+    /// Number of files that had to be hashed
+    pub fn misses(&self) -> u64 {
+        (self.fresh.len() + self.failed.len()) as u64
+    }
+
+    /// Combined hash of a file list; fails if any of the files could not be hashed.
+    pub fn combine(&self, files: Vec<PathBuf>) -> Result<HashResult, hasher::HashError> {
+        let file_count = files.len();
+        let mut hashes = Vec::with_capacity(file_count);
+        for file in files {
+            match self.hashes.get(&file) {
+                Some(&h) => hashes.push(h),
+                None => {
+                    let source = self.failed.get(&file).map_or_else(
+                        || std::io::Error::other("file was not hashed"),
+                        |e| std::io::Error::new(e.kind(), e.to_string()),
+                    );
+                    return Err(hasher::HashError::ReadFile { path: file, source });
+                }
+            }
+        }
+
+        let hash = hasher::aggregate_hashes(hashes);
+        Ok(HashResult { hash, file_count })
     }
 
-    let hash = hasher::aggregate_hashes(hashes);
-    Ok(HashResult { hash, file_count })
+    /// Combined hash of the readable files in a list. Files that could not be hashed
+    /// are returned with their error instead of failing the whole list.
+    pub fn combine_readable(
+        &mut self,
+        files: &[PathBuf],
+    ) -> (HashResult, Vec<(PathBuf, std::io::Error)>) {
+        let mut files = files.to_vec();
+        files.sort();
+        files.dedup();
+
+        let mut hashes = Vec::with_capacity(files.len());
+        let mut failed = Vec::new();
+        for file in files {
+            match self.hashes.get(&file) {
+                Some(&h) => hashes.push(h),
+                None => {
+                    let e = self
+                        .failed
+                        .remove(&file)
+                        .unwrap_or_else(|| std::io::Error::other("file was not hashed"));
+                    failed.push((file, e));
+                }
+            }
+        }
+
+        let file_count = hashes.len();
+        let hash = hasher::aggregate_hashes(hashes);
+        (HashResult { hash, file_count }, failed)
+    }
+}
+
+/// Hashes files on all available cores.
+fn hash_files_parallel(files: Vec<PathBuf>) -> Vec<(PathBuf, Result<u64, std::io::Error>)> {
+    let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let chunk_size = files.len().div_ceil(threads).max(1);
+
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = files
+            .chunks(chunk_size)
+            .map(|chunk| {
+                scope.spawn(move || {
+                    chunk
+                        .iter()
+                        .map(|file| (file.clone(), hasher::hash_file(file)))
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().unwrap_or_default())
+            .collect()
+    })
 }
@@ -1,4 +1,6 @@
-use ignore::WalkBuilder;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::{Match, Walk, WalkBuilder};
+use std::cmp::Reverse;
 use std::fs;
 use std::path::{Path, PathBuf};
 use thiserror::Error;
@@ -22,6 +24,22 @@ pub enum HashError {
     Watch(String),
 }
 
+/// Cloned for every request waiting on the same computation. `io::Error` is not
+/// `Clone`, so read errors are copied by kind and message.
+impl Clone for HashError {
+    fn clone(&self) -> Self {
+        match self {
+            HashError::GlobError(e) => HashError::GlobError(e.clone()),
+            HashError::ReadFile { path, source } => HashError::ReadFile {
+                path: path.clone(),
+                source: std::io::Error::new(source.kind(), source.to_string()),
+            },
+            HashError::NoFilesMatched => HashError::NoFilesMatched,
+            HashError::Watch(e) => HashError::Watch(e.clone()),
+        }
+    }
+}
+
 /// Hash a single file
 pub fn hash_file(path: &Path) -> Result<u64, std::io::Error> {
     let contents = fs::read(path)?;
@@ -40,22 +58,14 @@ pub fn aggregate_hashes(mut hashes: Vec<u64>) -> u64 {
 }
 
 /// List files matching a glob pattern in a directory
-pub fn list_files(root: &Path, path: &str, glob_pattern: &str) -> Result<Vec<PathBuf>, HashError> {
+pub fn list_files(root: &Path, path: &Path, glob_pattern: &str) -> Result<Vec<PathBuf>, HashError> {
     let full_path = root.join(path);
 
     // Build glob matcher
     let glob = globset::Glob::new(glob_pattern)?.compile_matcher();
 
-    // Walk with gitignore support
-    let walker = WalkBuilder::new(&full_path)
-        .git_ignore(true)
-        .git_global(true)
-        .git_exclude(true)
-        .follow_links(false)
-        .build();
-
     let mut files = Vec::new();
-    for entry in walker.filter_map(Result::ok) {
+    for entry in walk(&full_path).filter_map(Result::ok) {
         let entry_path = entry.path();
         if entry_path.is_file() {
             // Match glob against relative path from base directory
@@ -73,3 +83,304 @@ pub fn list_files(root: &Path, path: &str, glob_pattern: &str) -> Result<Vec<Pat
 
     Ok(files)
 }
+
+/// List files for several path/glob specs under one root, sharing a single walk.
+///
+/// Returns one result per spec, in order. Specs whose directory the shared walk does not
+/// enter (e.g. an ignored directory) fall back to `list_files`.
+pub fn list_files_many(
+    root: &Path,
+    specs: &[(&Path, &str)],
+) -> Vec<Result<Vec<PathBuf>, HashError>> {
+    let dirs: Vec<PathBuf> = specs.iter().map(|(path, _)| root.join(path)).collect();
+    let globs: Vec<Result<globset::GlobMatcher, globset::Error>> = specs
+        .iter()
+        .map(|(_, glob)| Ok(globset::Glob::new(glob)?.compile_matcher()))
+        .collect();
+
+    let spec_dirs = dirs.clone();
+    let walker = WalkBuilder::new(root)
+        .hidden(false)
+        .git_ignore(true)
+        .git_global(true)
+        .git_exclude(true)
+        .follow_links(false)
+        .filter_entry(move |entry| walks_into(&spec_dirs, entry.path()))
+        .build();
+
+    let mut files: Vec<Vec<PathBuf>> = vec![Vec::new(); specs.len()];
+    let mut visited = vec![false; specs.len()];
+
+    for entry in walker.filter_map(Result::ok) {
+        let entry_path = entry.path();
+        let is_dir = entry.file_type().is_some_and(|t| t.is_dir());
+        // Like `list_files`, follow symlinks to files but skip dangling links and
+        // links to directories
+        let is_file = !is_dir && entry_path.is_file();
+
+        for (i, dir) in dirs.iter().enumerate() {
+            if is_dir {
+                visited[i] |= entry_path == dir;
+                continue;
+            }
+            if !is_file {
+                continue;
+            }
+            let (Ok(rel_path), Ok(glob)) = (entry_path.strip_prefix(dir), &globs[i]) else {
+                continue;
+            };
+            if !is_hidden(rel_path) && glob.is_match(rel_path) {
+                files[i].push(entry_path.to_path_buf());
+            }
+        }
+    }
+
+    specs
+        .iter()
+        .zip(globs)
+        .zip(files.into_iter().zip(visited))
+        .map(|(((path, glob), matcher), (files, visited))| {
+            matcher?;
+            if !visited {
+                return list_files(root, path, glob);
+            }
+            if files.is_empty() {
+                return Err(HashError::NoFilesMatched);
+            }
+            Ok(files)
+        })
+        .collect()
+}
+
+/// Whether the shared walk of `list_files_many` enters `path`: only directories on the way
+/// to a spec directory, and entries inside one. Hidden entries inside a spec directory are
+/// skipped like `list_files` does.
+fn walks_into(spec_dirs: &[PathBuf], path: &Path) -> bool {
+    spec_dirs.iter().any(|dir| {
+        dir.starts_with(path)
+            || (path.starts_with(dir)
+                && !is_hidden(Path::new(path.file_name().unwrap_or_default())))
+    })
+}
+
+/// Walk a directory with gitignore support
+fn walk(dir: &Path) -> Walk {
+    WalkBuilder::new(dir)
+        .git_ignore(true)
+        .git_global(true)
+        .git_exclude(true)
+        .follow_links(false)
+        .build()
+}
+
+/// Whether a path relative to the walk directory is skipped as hidden by `list_files`
+pub fn is_hidden(rel_path: &Path) -> bool {
+    rel_path
+        .components()
+        .any(|c| c.as_os_str().to_string_lossy().starts_with('.'))
+}
+
+/// Whether a changed file is an ignore file that affects `IgnoreMatcher`
+pub fn is_ignore_file(path: &Path) -> bool {
+    match path.file_name().and_then(|name| name.to_str()) {
+        Some(".gitignore" | ".ignore") => true,
+        Some("exclude") => path.ends_with(".git/info/exclude"),
+        _ => false,
+    }
+}
+
+/// The ignore rules `list_files` applies while walking, for matching individual paths
+/// such as watcher events.
+pub struct IgnoreMatcher {
+    /// Per-directory ignore files, deepest directory first
+    matchers: Vec<Gitignore>,
+    /// Global gitignore, matched relative to the repository root
+    global: Option<(PathBuf, Gitignore)>,
+}
+
+impl IgnoreMatcher {
+    /// Collect the ignore files for a root: its parents up to the repository root,
+    /// and every directory the walk would enter.
+    pub fn build(root: &Path) -> Self {
+        let root = root.canonicalize().unwrap_or_else(|_| root.to_path_buf());
+        let git_root = root
+            .ancestors()
+            .find(|dir| dir.join(".git").exists())
+            .map(Path::to_path_buf);
+
+        let mut matchers = Vec::new();
+        let mut add_dir = |dir: &Path| {
+            // Same precedence as the walker: .ignore, then .gitignore, then exclude
+            matchers.extend(load_ignore_file(dir, &dir.join(".ignore")));
+            if git_root.is_some() {
+                matchers.extend(load_ignore_file(dir, &dir.join(".gitignore")));
+                matchers.extend(load_ignore_file(dir, &dir.join(".git/info/exclude")));
+            }
+        };
+
+        for dir in root.ancestors().skip(1) {
+            add_dir(dir);
+            if git_root.as_deref() == Some(dir) {
+                break;
+            }
+        }
+        for entry in walk(&root).filter_map(Result::ok) {
+            if entry.file_type().is_some_and(|t| t.is_dir()) {
+                add_dir(entry.path());
+            }
+        }
+
+        // Stable sort keeps per-directory precedence
+        matchers.sort_by_key(|gi| Reverse(gi.path().components().count()));
+
+        let global = git_root.map(|git_root| (git_root, Gitignore::global().0));
+
+        Self { matchers, global }
+    }
+
+    /// Whether a path would be skipped by the ignore rules of `list_files`
+    pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        for gi in &self.matchers {
+            let Ok(rel_path) = path.strip_prefix(gi.path()) else {
+                continue;
+            };
+            match match_with_parents(gi, rel_path, is_dir) {
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+                Match::None => {}
+            }
+        }
+
+        if let Some((git_root, gi)) = &self.global
+            && let Ok(rel_path) = path.strip_prefix(git_root)
+        {
+            return match_with_parents(gi, rel_path, is_dir).is_ignore();
+        }
+
+        false
+    }
+}
+
+/// Load an ignore file whose patterns are relative to `dir`
+fn load_ignore_file(dir: &Path, file: &Path) -> Option<Gitignore> {
+    if !file.is_file() {
+        return None;
+    }
+    let mut builder = GitignoreBuilder::new(dir);
+    builder.add(file);
+    builder.build().ok().filter(|gi| !gi.is_empty())
+}
+
+/// Match a relative path, then each of its parent directories
+fn match_with_parents<'a>(
+    gi: &'a Gitignore,
+    rel_path: &Path,
+    is_dir: bool,
+) -> Match<&'a ignore::gitignore::Glob> {
+    let mut matched = gi.matched(rel_path, is_dir);
+    let mut current = rel_path;
+    while matched.is_none() {
+        match current.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => {
+                matched = gi.matched(parent, true);
+                current = parent;
+            }
+            _ => break,
+        }
+    }
+    matched
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_list_files_many_skips_unrelated_dirs() {
+        let root = Path::new("/repo");
+        let dirs = vec![root.join("src"), root.join(".config/app")];
+
+        assert!(walks_into(&dirs, root));
+        assert!(walks_into(&dirs, &root.join("src")));
+        assert!(walks_into(&dirs, &root.join("src/a/lib.rs")));
+        assert!(walks_into(&dirs, &root.join(".config")));
+        assert!(walks_into(&dirs, &root.join(".config/app/x.toml")));
+        assert!(!walks_into(&dirs, &root.join("docs")));
+        assert!(!walks_into(&dirs, &root.join("srcs")));
+        assert!(!walks_into(&dirs, &root.join("main.rs")));
+        assert!(!walks_into(&dirs, &root.join(".config/other")));
+        assert!(!walks_into(&dirs, &root.join("src/.hidden")));
+    }
+
+    #[test]
+    fn test_list_files_many_matches_list_files() {
+        let temp_dir = std::env::temp_dir().join("fswatchd-test-list-many");
+        let _ = std::fs::remove_dir_all(&temp_dir);
+        let _ = std::fs::create_dir_all(temp_dir.join(".git"));
+        let _ = std::fs::create_dir_all(temp_dir.join("src/.hidden"));
+        let _ = std::fs::create_dir_all(temp_dir.join(".github"));
+        let _ = std::fs::create_dir_all(temp_dir.join("target"));
+        let _ = std::fs::write(temp_dir.join(".gitignore"), "target/\n");
+        let _ = std::fs::write(temp_dir.join("src/lib.rs"), "");
+        let _ = std::fs::write(temp_dir.join("src/.hidden/x.rs"), "");
+        let _ = std::fs::write(temp_dir.join(".github/ci.yml"), "");
+        let _ = std::fs::write(temp_dir.join("target/out.rs"), "");
+        let _ = std::fs::write(temp_dir.join("main.rs"), "");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::symlink;
+            let _ = symlink(temp_dir.join("main.rs"), temp_dir.join("src/link.rs"));
+            let _ = symlink(temp_dir.join("src"), temp_dir.join("src/dir.rs"));
+            let _ = symlink(temp_dir.join("gone.rs"), temp_dir.join("src/dangling.rs"));
+        }
+
+        let specs = [
+            (".", "**/*.rs"),
+            ("src", "*.rs"),
+            (".github", "*.yml"),
+            ("target", "*.rs"),
+            ("src", "*.txt"),
+            ("src", "["),
+        ];
+        let specs: Vec<(&Path, &str)> = specs
+            .iter()
+            .map(|(path, glob)| (Path::new(*path), *glob))
+            .collect();
+        let many = list_files_many(&temp_dir, &specs);
+        assert_eq!(many.len(), specs.len());
+
+        for ((path, glob), result) in specs.iter().zip(many) {
+            match (result, list_files(&temp_dir, path, glob)) {
+                (Ok(mut a), Ok(mut b)) => {
+                    a.sort();
+                    b.sort();
+                    assert_eq!(a, b, "{} {}", path.display(), glob);
+                }
+                (Err(a), Err(b)) => assert_eq!(a.to_string(), b.to_string()),
+                (a, b) => panic!("{} {}: {:?} vs {:?}", path.display(), glob, a, b),
+            }
+        }
+
+        let _ = std::fs::remove_dir_all(&temp_dir);
+    }
+
+    #[test]
+    fn test_ignore_matcher_follows_gitignore() {
+        let temp_dir = std::env::temp_dir().join("fswatchd-test-ignore");
+        let _ = std::fs::remove_dir_all(&temp_dir);
+        let _ = std::fs::create_dir_all(temp_dir.join(".git"));
+        let _ = std::fs::create_dir_all(temp_dir.join("target/debug"));
+        let _ = std::fs::create_dir_all(temp_dir.join("src"));
+        let _ = std::fs::write(temp_dir.join(".gitignore"), "target/\n");
+        let _ = std::fs::write(temp_dir.join("src/.gitignore"), "gen_*.rs\n!gen_keep.rs\n");
+
+        let matcher = IgnoreMatcher::build(&temp_dir);
+        let root = temp_dir.canonicalize().unwrap();
+        assert!(matcher.is_ignored(&root.join("target/debug/foo.rs"), false));
+        assert!(matcher.is_ignored(&root.join("src/gen_a.rs"), false));
+        assert!(!matcher.is_ignored(&root.join("src/gen_keep.rs"), false));
+        assert!(!matcher.is_ignored(&root.join("src/lib.rs"), false));
+
+        let _ = std::fs::remove_dir_all(&temp_dir);
+    }
+}
@@ -1,4 +1,7 @@
+mod clock;
 mod daemon;
+mod debounce;
+mod framing;
 mod hash_service;
 mod hasher;
 mod logging;
@@ -6,6 +9,7 @@ mod persistence;
 mod protocol;
 mod server;
 mod session;
+mod subscriptions;
 mod transport;
 
 use clap::{Parser, Subcommand};
@@ -23,9 +27,35 @@ struct Cli {
 enum Commands {
     /// Start the daemon server
     Start {
-        /// Custom socket path (Unix) or pipe name (Windows)
+        /// Custom socket path (Unix) or pipe name (Windows); defaults to $FSWATCHD_SOCKET or a
+        /// per-user path
         #[arg(long)]
         socket_path: Option<String>,
+        /// Change events buffered for each client before it is sent an overflow event
+        #[arg(long, default_value_t = server::DEFAULT_EVENT_BUFFER)]
+        event_buffer: usize,
+        /// Watcher events queued for the daemon; when it is full, subscriptions on the
+        /// affected roots are sent an overflow event
+        #[arg(long, default_value_t = server::DEFAULT_WATCHER_BUFFER)]
+        watcher_buffer: usize,
+        /// Replies to concurrent requests queued for each client
+        #[arg(long, default_value_t = server::DEFAULT_REPLY_BUFFER)]
+        reply_buffer: usize,
+        /// Concurrent client connections; more are refused with an error
+        #[arg(long, default_value_t = server::DEFAULT_MAX_CONNECTIONS)]
+        max_connections: usize,
+        /// Largest request line or frame in bytes
+        #[arg(long, default_value_t = framing::DEFAULT_MAX_REQUEST_LEN)]
+        max_request_bytes: usize,
+        /// Deadline of hash and query requests in milliseconds (0 disables it)
+        #[arg(long, default_value_t = server::DEFAULT_REQUEST_TIMEOUT_MS)]
+        request_timeout_ms: u64,
+        /// Subscriptions one client may hold
+        #[arg(long, default_value_t = server::DEFAULT_MAX_SUBSCRIPTIONS)]
+        max_subscriptions: usize,
+        /// Requests with an `id` one client may have in flight; more get an error
+        #[arg(long, default_value_t = server::DEFAULT_MAX_CONCURRENT_REQUESTS)]
+        max_concurrent_requests: usize,
     },
 }
 
@@ -35,8 +65,28 @@ fn main() {
     let cli = Cli::parse();
 
     match cli.command {
-        Commands::Start { socket_path } => {
-            if let Err(e) = server::run(socket_path) {
+        Commands::Start {
+            socket_path,
+            event_buffer,
+            watcher_buffer,
+            reply_buffer,
+            max_connections,
+            max_request_bytes,
+            request_timeout_ms,
+            max_subscriptions,
+            max_concurrent_requests,
+        } => {
+            let options = server::ServerOptions {
+                event_buffer,
+                watcher_buffer,
+                reply_buffer,
+                max_connections,
+                max_request_len: max_request_bytes,
+                request_timeout_ms,
+                max_subscriptions,
+                max_concurrent_requests,
+            };
+            if let Err(e) = server::run(socket_path, options) {
                 error!("Server error: {}", e);
             }
         }
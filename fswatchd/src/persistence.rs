@@ -4,23 +4,84 @@ use serde::{Deserialize, Serialize};
 use std::collections::HashSet;
 use std::fs;
 use std::hash::{Hash, Hasher};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::protocol::{self, EntryOrigin, SubscriptionKey, WatchOptions};
 
 const STATE_DIR: &str = ".fswatchd";
 const STATE_FILE: &str = "state.json";
 
-#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct WatchEntry {
     pub root: PathBuf,
-    pub path: String,
+    pub path: PathBuf,
     pub glob: String,
+    #[serde(default, skip_serializing_if = "WatchOptions::is_default")]
+    pub options: WatchOptions,
+    #[serde(default)]
+    pub origin: EntryOrigin,
+    /// Unix seconds when the entry was added
+    #[serde(default)]
+    pub created_at: u64,
+    /// Unix seconds when a client last requested the entry
+    #[serde(default)]
+    pub last_access: u64,
+}
+
+impl WatchEntry {
+    pub fn new(
+        root: PathBuf,
+        path: PathBuf,
+        glob: String,
+        options: WatchOptions,
+        origin: EntryOrigin,
+    ) -> Self {
+        let now = unix_now();
+        Self {
+            root,
+            path,
+            glob,
+            options,
+            origin,
+            created_at: now,
+            last_access: now,
+        }
+    }
+
+    /// Subscription key this entry is registered under.
+    pub fn key(&self) -> SubscriptionKey {
+        match self.origin {
+            EntryOrigin::Watch => {
+                protocol::make_watch_key(&self.root, &self.path, &self.glob, &self.options)
+            }
+            EntryOrigin::HashPersistent => {
+                protocol::make_persistent_hash_key(&self.root, &self.path, &self.glob)
+            }
+        }
+    }
 }
 
+// Identity excludes the access timestamps
+impl PartialEq for WatchEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.root == other.root
+            && self.path == other.path
+            && self.glob == other.glob
+            && self.options == other.options
+            && self.origin == other.origin
+    }
+}
+
+impl Eq for WatchEntry {}
+
 impl Hash for WatchEntry {
     fn hash<H: Hasher>(&self, state: &mut H) {
         self.root.hash(state);
         self.path.hash(state);
         self.glob.hash(state);
+        self.options.hash(state);
+        self.origin.hash(state);
     }
 }
 
@@ -29,6 +90,41 @@ pub struct PersistedState {
     pub watch_entries: HashSet<WatchEntry>,
 }
 
+impl PersistedState {
+    /// Insert an entry, or refresh the access time of an existing one.
+    /// Returns `true` if the entry is new.
+    pub fn record(&mut self, mut entry: WatchEntry) -> bool {
+        match self.watch_entries.take(&entry) {
+            Some(existing) => {
+                entry.created_at = existing.created_at;
+                entry.last_access = unix_now();
+                self.watch_entries.insert(entry);
+                false
+            }
+            None => self.watch_entries.insert(entry),
+        }
+    }
+}
+
+/// Check that a root and path can be persisted. State is stored as JSON, which only
+/// holds UTF-8 paths.
+pub fn check_persistable(root: &Path, path: &Path) -> Result<(), String> {
+    match [root, path].into_iter().find(|p| p.to_str().is_none()) {
+        Some(p) => Err(format!(
+            "Path {} is not valid UTF-8 and cannot be persisted",
+            p.to_string_lossy()
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Current time in Unix seconds.
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
 /// Returns the path to the state file (~/.fs-hasher/state.json).
 fn state_file_path() -> Option<PathBuf> {
     dirs::home_dir().map(|home| home.join(STATE_DIR).join(STATE_FILE))
@@ -63,3 +159,57 @@ pub fn save(state: &PersistedState) -> Result<(), std::io::Error> {
     eprintln!("Saved state to: {}", path.display());
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(origin: EntryOrigin) -> WatchEntry {
+        WatchEntry::new(
+            PathBuf::from("/repo"),
+            PathBuf::from("src"),
+            "*.rs".to_string(),
+            WatchOptions::default(),
+            origin,
+        )
+    }
+
+    #[test]
+    fn test_record_refreshes_existing_entry() {
+        let mut state = PersistedState::default();
+        let mut first = entry(EntryOrigin::Watch);
+        first.created_at = 1;
+        first.last_access = 1;
+
+        assert!(state.record(first));
+        assert!(!state.record(entry(EntryOrigin::Watch)));
+        assert_eq!(state.watch_entries.len(), 1);
+
+        let stored = state.watch_entries.iter().next().unwrap();
+        assert_eq!(stored.created_at, 1);
+        assert!(stored.last_access > 1);
+
+        // Same spec from a persistent hash is tracked separately, under its own key
+        assert!(state.record(entry(EntryOrigin::HashPersistent)));
+        assert_eq!(state.watch_entries.len(), 2);
+        assert_ne!(
+            entry(EntryOrigin::Watch).key(),
+            entry(EntryOrigin::HashPersistent).key()
+        );
+    }
+
+    #[test]
+    fn test_load_entries_without_metadata() {
+        let state: PersistedState = serde_json::from_str(
+            r#"{"watch_entries":[{"root":"/repo","path":"src","glob":"*.rs"}]}"#,
+        )
+        .unwrap();
+        let stored = state.watch_entries.iter().next().unwrap();
+        assert_eq!(stored.origin, EntryOrigin::Watch);
+        assert_eq!(stored.created_at, 0);
+        assert_eq!(
+            stored.key(),
+            protocol::make_subscription_key(Path::new("/repo"), Path::new("src"), "*.rs")
+        );
+    }
+}
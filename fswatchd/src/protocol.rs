@@ -3,55 +3,598 @@
 //! This module contains all protocol-related types and logic, making it easy to test
 //! serialization/deserialization without needing actual socket connections.
 
-use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use xxhash_rust::xxh3::xxh3_128;
 
 /// Subscription key type (128-bit xxh3 hash as 32-char hex string)
 pub type SubscriptionKey = String;
 
+/// Newest protocol version this daemon speaks
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Oldest protocol version this daemon still accepts
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Commands understood by this daemon
+pub const COMMANDS: &[&str] = &[
+    "hello",
+    "hash",
+    "hash_many",
+    "hash_files",
+    "watch",
+    "unwatch",
+    "status",
+    "list",
+    "invalidate",
+    "since",
+    "shutdown",
+];
+
+/// Request options understood by this daemon
+pub const OPTIONS: &[&str] = &[
+    "id",
+    "persistent",
+    "content_only",
+    "include_hash",
+    "debounce_ms",
+    "max_delay_ms",
+    "quiet_ms",
+    "rehash",
+    "sync",
+    "durable",
+];
+
+/// Content hash algorithms used by this daemon
+pub const ALGORITHMS: &[&str] = &["xxh3-64"];
+
+/// Wire framings a client can switch to with `hello`
+pub const FRAMINGS: &[&str] = &["ndjson", "msgpack"];
+
 /// Request types from client
 #[derive(Debug, Clone, PartialEq, Deserialize)]
 #[serde(tag = "cmd", rename_all = "lowercase")]
 pub enum Request {
+    Hello {
+        /// Newest protocol version the client speaks
+        protocol: u32,
+        /// Framing to use after the `hello` response
+        #[serde(default)]
+        framing: Framing,
+    },
     Hash {
-        root: String,
-        path: String,
+        root: WirePath,
+        path: WirePath,
         glob: String,
         #[serde(default)]
         persistent: bool,
+        /// Wait until the watcher has seen all writes made before the request
+        #[serde(default)]
+        sync: bool,
+    },
+    #[serde(rename = "hash_many")]
+    HashMany {
+        specs: Vec<HashSpec>,
+        #[serde(default)]
+        sync: bool,
+    },
+    /// Combined hash of an explicit list of files, relative to `root`
+    #[serde(rename = "hash_files")]
+    HashFiles {
+        root: WirePath,
+        files: Vec<WirePath>,
+        #[serde(default)]
+        sync: bool,
     },
     Watch {
-        root: String,
-        path: String,
+        root: WirePath,
+        path: WirePath,
         glob: String,
+        #[serde(flatten)]
+        options: WatchOptions,
+        /// Keep the subscription after the client disconnects and across restarts
+        #[serde(default)]
+        durable: bool,
     },
     Unwatch {
-        key: String,
+        #[serde(flatten)]
+        target: UnwatchTarget,
+    },
+    Status,
+    List,
+    Invalidate {
+        #[serde(flatten)]
+        scope: InvalidateScope,
+        /// Recompute the dropped results in the background
+        #[serde(default)]
+        rehash: bool,
+    },
+    /// Files matching path/glob that changed after a clock token
+    Since {
+        root: WirePath,
+        path: WirePath,
+        glob: String,
+        clock: String,
+        #[serde(default)]
+        sync: bool,
     },
+    Shutdown,
+}
+
+impl Request {
+    /// Whether the request leaves session state untouched and can run concurrently
+    pub fn is_stateless(&self) -> bool {
+        matches!(
+            self,
+            Request::Hash { .. }
+                | Request::HashMany { .. }
+                | Request::HashFiles { .. }
+                | Request::Status
+                | Request::List
+                | Request::Invalidate { .. }
+                | Request::Since { .. }
+        )
+    }
+}
+
+/// What an `invalidate` request clears
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum InvalidateScope {
+    /// Everything cached for a subscription or persisted entry
+    Key { key: SubscriptionKey },
+    /// Everything cached under `root/path` (the whole root without `path`)
+    Root {
+        root: WirePath,
+        #[serde(default)]
+        path: Option<WirePath>,
+    },
+}
+
+/// What an `unwatch` request removes
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum UnwatchTarget {
+    /// The subscription or persisted entry with this key
+    Key { key: SubscriptionKey },
+    /// Every subscription and persisted entry for this root/path/glob, whatever its options
+    Spec {
+        root: WirePath,
+        path: WirePath,
+        glob: String,
+    },
+}
+
+/// One path/glob spec of a `hash_many` request
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct HashSpec {
+    pub root: WirePath,
+    pub path: WirePath,
+    pub glob: String,
+    #[serde(default)]
+    pub persistent: bool,
+}
+
+/// Client-chosen request correlation ID, echoed back in the reply
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum RequestId {
+    Number(u64),
+    String(String),
+}
+
+/// A request line: optional correlation ID plus the request itself
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct RequestEnvelope {
+    #[serde(default)]
+    pub id: Option<RequestId>,
+    #[serde(flatten)]
+    pub request: Request,
+}
+
+/// Per-subscription options for watch requests
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct WatchOptions {
+    /// Rehash changed files and only report paths whose content actually changed
+    #[serde(default)]
+    pub content_only: bool,
+    /// Recompute the aggregate hash after each batch and include it in the event
+    #[serde(default)]
+    pub include_hash: bool,
+    /// Report a path once it has had no events for this many ms (default 100)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub debounce_ms: Option<u64>,
+    /// Flush a batch at most this many ms after its first event
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_delay_ms: Option<u64>,
+    /// Hold the batch until no events arrived for this many ms
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quiet_ms: Option<u64>,
+}
+
+impl WatchOptions {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
 }
 
 /// Response types to client
 #[derive(Debug, Clone, PartialEq, Serialize)]
 #[serde(untagged)]
 pub enum Response {
-    Hash { hash: String, file_count: usize },
-    Watch { key: String },
-    Ok { ok: bool },
-    Error { error: String },
+    Hello {
+        protocol: u32,
+        version: String,
+        capabilities: Capabilities,
+        framing: Framing,
+    },
+    Hash {
+        hash: String,
+        file_count: usize,
+        /// Clock of the root when hashed (only for watched roots)
+        #[serde(skip_serializing_if = "Option::is_none")]
+        clock: Option<String>,
+    },
+    HashMany {
+        results: Vec<HashOutcome>,
+    },
+    HashFiles {
+        hash: String,
+        file_count: usize,
+        /// Files that could not be read; the hash covers the others
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        missing: Vec<FileError>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        clock: Option<String>,
+    },
+    Since {
+        clock: String,
+        /// The daemon cannot answer for the given clock; the client must start fresh
+        fresh_instance: bool,
+        files: Vec<ChangedFile>,
+    },
+    Status(DaemonStatus),
+    Invalidated {
+        files: usize,
+        results: usize,
+    },
+    List {
+        subscriptions: Vec<ListEntry>,
+        persisted: Vec<ListEntry>,
+    },
+    Watch {
+        key: String,
+    },
+    Ok {
+        ok: bool,
+    },
+    Error {
+        error: String,
+    },
+}
+
+/// Per-spec result of a `hash_many` request
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum HashOutcome {
+    Hash {
+        hash: String,
+        file_count: usize,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        clock: Option<String>,
+    },
+    Error {
+        error: String,
+    },
+}
+
+/// A file of a `hash_files` request that could not be hashed
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FileError {
+    pub path: WirePath,
+    pub error: String,
+}
+
+/// A file reported by `since`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ChangedFile {
+    pub path: WirePath,
+    /// `false` if the file was deleted
+    pub exists: bool,
+}
+
+/// Daemon internals reported by `status`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DaemonStatus {
+    pub version: String,
+    pub uptime_secs: u64,
+    pub roots: Vec<WatchedRoot>,
+    pub subscriptions: usize,
+    pub persisted_entries: usize,
+    pub connected_clients: usize,
+    pub file_cache: CacheStatus,
+    pub result_cache: CacheStatus,
+    /// Changed paths waiting for cache invalidation
+    pub pending_invalidations: usize,
+    /// Invalidated paths waiting in subscription batches
+    pub pending_notifications: usize,
+    /// Watcher events dropped because the event queue was full
+    pub dropped_events: u64,
+    /// Subscription events missed by clients that fell behind
+    pub lagged_events: u64,
+}
+
+/// A watched root and its watcher backend
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct WatchedRoot {
+    pub root: WirePath,
+    pub backend: String,
+}
+
+/// Size and hit/miss counters of a cache
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct CacheStatus {
+    pub entries: usize,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// How a subscription or persisted entry was created
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EntryOrigin {
+    /// A `hash` request with `persistent: true`
+    HashPersistent,
+    /// A `watch` request
+    #[default]
+    Watch,
+}
+
+/// A subscription or persisted entry reported by `list`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ListEntry {
+    pub key: SubscriptionKey,
+    pub root: WirePath,
+    pub path: WirePath,
+    pub glob: String,
+    #[serde(skip_serializing_if = "WatchOptions::is_default")]
+    pub options: WatchOptions,
+    pub origin: EntryOrigin,
+    /// Unix seconds
+    pub created_at: u64,
+    /// Unix seconds
+    pub last_access: u64,
+    /// Connected clients holding the subscription
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub clients: Option<usize>,
+}
+
+/// Features supported by the daemon, reported by `hello`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Capabilities {
+    pub commands: Vec<String>,
+    pub options: Vec<String>,
+    pub algorithms: Vec<String>,
+    pub framings: Vec<String>,
+}
+
+impl Capabilities {
+    pub fn current() -> Self {
+        let to_vec = |items: &[&str]| items.iter().map(|s| s.to_string()).collect();
+        Self {
+            commands: to_vec(COMMANDS),
+            options: to_vec(OPTIONS),
+            algorithms: to_vec(ALGORITHMS),
+            framings: to_vec(FRAMINGS),
+        }
+    }
+}
+
+/// How messages are delimited on a connection
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Framing {
+    /// One JSON document per line
+    #[default]
+    Ndjson,
+    /// MessagePack documents, each prefixed with its length as a big-endian u32
+    Msgpack,
+}
+
+/// A filesystem path: a (lossy) string in JSON, the raw OS bytes in MessagePack
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct WirePath(pub PathBuf);
+
+impl From<PathBuf> for WirePath {
+    fn from(path: PathBuf) -> Self {
+        Self(path)
+    }
+}
+
+impl From<&str> for WirePath {
+    fn from(path: &str) -> Self {
+        Self(PathBuf::from(path))
+    }
+}
+
+impl AsRef<Path> for WirePath {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl std::ops::Deref for WirePath {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Serialize for WirePath {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&self.0.to_string_lossy())
+        } else {
+            serializer.serialize_bytes(self.0.as_os_str().as_encoded_bytes())
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for WirePath {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl serde::de::Visitor<'_> for Visitor {
+            type Value = WirePath;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a path string or bytes")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<WirePath, E> {
+                Ok(WirePath(PathBuf::from(v)))
+            }
+
+            fn visit_bytes<E: serde::de::Error>(self, v: &[u8]) -> Result<WirePath, E> {
+                #[cfg(unix)]
+                let path =
+                    PathBuf::from(<std::ffi::OsStr as std::os::unix::ffi::OsStrExt>::from_bytes(v));
+                #[cfg(not(unix))]
+                let path = PathBuf::from(String::from_utf8_lossy(v).into_owned());
+                Ok(WirePath(path))
+            }
+        }
+
+        deserializer.deserialize_any(Visitor)
+    }
+}
+
+/// Pick the protocol version to use with a client, if any is mutually supported.
+pub fn negotiate_version(client: u32) -> Option<u32> {
+    let version = client.min(PROTOCOL_VERSION);
+    (version >= MIN_PROTOCOL_VERSION).then_some(version)
 }
 
 /// Subscription event pushed to client
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct SubscriptionEvent {
     pub key: String,
-    pub paths: Vec<String>,
+    pub paths: Vec<WirePath>,
+    /// Aggregate hash after this batch (only with `include_hash`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file_count: Option<usize>,
+    /// Error recomputing the aggregate hash (only with `include_hash`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Events for this key were dropped; the client should rehash
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub overflow: bool,
+}
+
+impl SubscriptionEvent {
+    /// Tells a lagging client that events for `key` were lost
+    pub fn overflow(key: SubscriptionKey) -> Self {
+        Self {
+            key,
+            paths: Vec::new(),
+            hash: None,
+            file_count: None,
+            error: None,
+            overflow: true,
+        }
+    }
+}
+
+/// Every outgoing line, tagged with its `type`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Message {
+    Response {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id: Option<RequestId>,
+        #[serde(flatten)]
+        response: Response,
+    },
+    Error {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id: Option<RequestId>,
+        error: String,
+    },
+    Event(SubscriptionEvent),
+    /// The daemon is shutting down and will close the connection
+    Shutdown,
+}
+
+impl Message {
+    /// Wrap a response to the request with the given ID; errors become `error` messages.
+    pub fn reply(id: Option<RequestId>, response: Response) -> Self {
+        match response {
+            Response::Error { error } => Message::Error { id, error },
+            response => Message::Response { id, response },
+        }
+    }
+}
+
+/// Parse a request line. On failure, returns the request ID if one could still be read.
+pub fn parse_request(line: &str) -> Result<RequestEnvelope, (Option<RequestId>, String)> {
+    serde_json::from_str::<RequestEnvelope>(line).map_err(|e| {
+        let id = serde_json::from_str::<serde_json::Value>(line)
+            .ok()
+            .and_then(|value| value.get("id").cloned())
+            .and_then(|id| serde_json::from_value(id).ok());
+        (id, format!("Invalid request: {}", e))
+    })
+}
+
+/// NUL-separated raw bytes of root/path/glob, the input of subscription keys
+fn key_input(root: &Path, path: &Path, glob: &str) -> Vec<u8> {
+    let mut input = Vec::new();
+    input.extend_from_slice(root.as_os_str().as_encoded_bytes());
+    input.push(0);
+    input.extend_from_slice(path.as_os_str().as_encoded_bytes());
+    input.push(0);
+    input.extend_from_slice(glob.as_bytes());
+    input
 }
 
 /// Generate deterministic 128-bit subscription key from root/path/glob.
-pub fn make_subscription_key(root: &str, path: &str, glob: &str) -> SubscriptionKey {
-    let input = format!("{}\0{}\0{}", root, path, glob);
-    let hash = xxh3_128(input.as_bytes());
-    format!("{:032x}", hash)
+pub fn make_subscription_key(root: &Path, path: &Path, glob: &str) -> SubscriptionKey {
+    format!("{:032x}", xxh3_128(&key_input(root, path, glob)))
+}
+
+/// Generate subscription key for a watch request.
+///
+/// Default options map to the plain root/path/glob key, so existing keys stay stable.
+pub fn make_watch_key(
+    root: &Path,
+    path: &Path,
+    glob: &str,
+    options: &WatchOptions,
+) -> SubscriptionKey {
+    if options.is_default() {
+        return make_subscription_key(root, path, glob);
+    }
+    let mut input = key_input(root, path, glob);
+    input.push(0);
+    input.extend_from_slice(
+        serde_json::to_string(options)
+            .unwrap_or_default()
+            .as_bytes(),
+    );
+    format!("{:032x}", xxh3_128(&input))
+}
+
+/// Generate the key of an entry added by a persistent hash, distinct from the key of a
+/// watch on the same spec.
+pub fn make_persistent_hash_key(root: &Path, path: &Path, glob: &str) -> SubscriptionKey {
+    let mut input = key_input(root, path, glob);
+    input.push(0);
+    input.extend_from_slice(b"hash_persistent");
+    format!("{:032x}", xxh3_128(&input))
 }
 
 #[cfg(test)]
@@ -60,18 +603,269 @@ mod tests {
 
     #[test]
     fn test_subscription_key_deterministic() {
-        let key1 = make_subscription_key("/repo", "src", "**/*.rs");
-        let key2 = make_subscription_key("/repo", "src", "**/*.rs");
+        let key1 = make_subscription_key(Path::new("/repo"), Path::new("src"), "**/*.rs");
+        let key2 = make_subscription_key(Path::new("/repo"), Path::new("src"), "**/*.rs");
         assert_eq!(key1, key2);
         assert_eq!(key1.len(), 32); // 128-bit hex
     }
 
     #[test]
     fn test_subscription_key_unique() {
-        let key1 = make_subscription_key("/repo", "src", "**/*.rs");
-        let key2 = make_subscription_key("/repo", "lib", "**/*.rs");
-        let key3 = make_subscription_key("/other", "src", "**/*.rs");
+        let key1 = make_subscription_key(Path::new("/repo"), Path::new("src"), "**/*.rs");
+        let key2 = make_subscription_key(Path::new("/repo"), Path::new("lib"), "**/*.rs");
+        let key3 = make_subscription_key(Path::new("/other"), Path::new("src"), "**/*.rs");
         assert_ne!(key1, key2);
         assert_ne!(key1, key3);
     }
+
+    #[test]
+    fn test_subscription_key_hashes_raw_path_bytes() {
+        // UTF-8 paths keep the keys of the earlier string format
+        let key = make_subscription_key(Path::new("/repo"), Path::new("src"), "*.rs");
+        assert_eq!(key, format!("{:032x}", xxh3_128(b"/repo\0src\0*.rs")));
+
+        #[cfg(unix)]
+        {
+            use std::ffi::OsStr;
+            use std::os::unix::ffi::OsStrExt;
+
+            let latin1 = make_subscription_key(
+                Path::new(OsStr::from_bytes(b"/caf\xe9")),
+                Path::new("src"),
+                "*.rs",
+            );
+            let lossy = make_subscription_key(Path::new("/caf\u{fffd}"), Path::new("src"), "*.rs");
+            assert_ne!(latin1, lossy);
+        }
+    }
+
+    #[test]
+    fn test_watch_key_options() {
+        let plain = make_subscription_key(Path::new("/repo"), Path::new("src"), "**/*.rs");
+        let default_key = make_watch_key(
+            Path::new("/repo"),
+            Path::new("src"),
+            "**/*.rs",
+            &WatchOptions::default(),
+        );
+        let content_key = make_watch_key(
+            Path::new("/repo"),
+            Path::new("src"),
+            "**/*.rs",
+            &WatchOptions {
+                content_only: true,
+                ..Default::default()
+            },
+        );
+        assert_eq!(plain, default_key);
+        assert_ne!(plain, content_key);
+    }
+
+    #[test]
+    fn test_parse_watch_options() {
+        let req: Request = serde_json::from_str(
+            r#"{"cmd":"watch","root":"/repo","path":"src","glob":"*.rs","content_only":true}"#,
+        )
+        .unwrap();
+        match req {
+            Request::Watch { options, .. } => assert!(options.content_only),
+            _ => panic!("Expected Watch"),
+        }
+    }
+
+    #[test]
+    fn test_negotiate_version() {
+        assert_eq!(negotiate_version(PROTOCOL_VERSION), Some(PROTOCOL_VERSION));
+        assert_eq!(
+            negotiate_version(PROTOCOL_VERSION + 5),
+            Some(PROTOCOL_VERSION)
+        );
+        assert_eq!(negotiate_version(MIN_PROTOCOL_VERSION - 1), None);
+    }
+
+    #[test]
+    fn test_advertised_commands_parse() {
+        for cmd in COMMANDS {
+            let err = serde_json::from_str::<Request>(&format!(r#"{{"cmd":"{}"}}"#, cmd))
+                .map(|_| String::new())
+                .unwrap_or_else(|e| e.to_string());
+            assert!(!err.contains("unknown variant"), "{}: {}", cmd, err);
+        }
+    }
+
+    #[test]
+    fn test_parse_request_with_id() {
+        let envelope =
+            parse_request(r#"{"id":7,"cmd":"hash","root":"/repo","path":"src","glob":"*.rs"}"#)
+                .unwrap();
+        assert_eq!(envelope.id, Some(RequestId::Number(7)));
+        assert!(envelope.request.is_stateless());
+
+        let envelope = parse_request(r#"{"cmd":"unwatch","key":"k"}"#).unwrap();
+        assert_eq!(envelope.id, None);
+        assert!(!envelope.request.is_stateless());
+    }
+
+    #[test]
+    fn test_parse_unwatch_target() {
+        let req: Request = serde_json::from_str(r#"{"cmd":"unwatch","key":"k"}"#).unwrap();
+        assert_eq!(
+            req,
+            Request::Unwatch {
+                target: UnwatchTarget::Key {
+                    key: "k".to_string()
+                }
+            }
+        );
+
+        let req: Request =
+            serde_json::from_str(r#"{"cmd":"unwatch","root":"/repo","path":"src","glob":"*.rs"}"#)
+                .unwrap();
+        assert_eq!(
+            req,
+            Request::Unwatch {
+                target: UnwatchTarget::Spec {
+                    root: "/repo".into(),
+                    path: "src".into(),
+                    glob: "*.rs".to_string(),
+                }
+            }
+        );
+
+        assert!(serde_json::from_str::<Request>(r#"{"cmd":"unwatch","root":"/repo"}"#).is_err());
+    }
+
+    #[test]
+    fn test_parse_hash_many() {
+        let req: Request = serde_json::from_str(
+            r#"{"cmd":"hash_many","specs":[{"root":"/repo","path":"src","glob":"*.rs"},{"root":"/repo","path":"lib","glob":"*.rs","persistent":true}]}"#,
+        )
+        .unwrap();
+        match req {
+            Request::HashMany { specs, sync } => {
+                assert!(!sync);
+                assert_eq!(specs.len(), 2);
+                assert!(!specs[0].persistent);
+                assert!(specs[1].persistent);
+            }
+            _ => panic!("Expected HashMany"),
+        }
+    }
+
+    #[test]
+    fn test_parse_hash_files() {
+        let req: Request =
+            serde_json::from_str(r#"{"cmd":"hash_files","root":"/repo","files":["a.c","b.h"]}"#)
+                .unwrap();
+        assert_eq!(
+            req,
+            Request::HashFiles {
+                root: "/repo".into(),
+                files: vec!["a.c".into(), "b.h".into()],
+                sync: false,
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_invalidate_scope() {
+        let req: Request =
+            serde_json::from_str(r#"{"cmd":"invalidate","key":"k","rehash":true}"#).unwrap();
+        assert_eq!(
+            req,
+            Request::Invalidate {
+                scope: InvalidateScope::Key {
+                    key: "k".to_string()
+                },
+                rehash: true,
+            }
+        );
+
+        let req: Request =
+            serde_json::from_str(r#"{"cmd":"invalidate","root":"/repo","path":"src"}"#).unwrap();
+        assert_eq!(
+            req,
+            Request::Invalidate {
+                scope: InvalidateScope::Root {
+                    root: "/repo".into(),
+                    path: Some("src".into()),
+                },
+                rehash: false,
+            }
+        );
+
+        assert!(serde_json::from_str::<Request>(r#"{"cmd":"invalidate"}"#).is_err());
+    }
+
+    #[test]
+    fn test_parse_request_error_keeps_id() {
+        let (id, error) = parse_request(r#"{"id":"a","cmd":"bogus"}"#).unwrap_err();
+        assert_eq!(id, Some(RequestId::String("a".to_string())));
+        assert!(error.starts_with("Invalid request"));
+    }
+
+    #[test]
+    fn test_message_tagging() {
+        let response = Message::reply(
+            Some(RequestId::Number(1)),
+            Response::Hash {
+                hash: "abc".to_string(),
+                file_count: 2,
+                clock: None,
+            },
+        );
+        assert_eq!(
+            serde_json::to_string(&response).unwrap(),
+            r#"{"type":"response","id":1,"hash":"abc","file_count":2}"#
+        );
+
+        let error = Message::reply(
+            None,
+            Response::Error {
+                error: "boom".to_string(),
+            },
+        );
+        assert_eq!(
+            serde_json::to_string(&error).unwrap(),
+            r#"{"type":"error","error":"boom"}"#
+        );
+
+        let event = Message::Event(SubscriptionEvent {
+            key: "k".to_string(),
+            paths: vec![],
+            hash: None,
+            file_count: None,
+            error: None,
+            overflow: false,
+        });
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"type":"event","key":"k","paths":[]}"#
+        );
+
+        let overflow = Message::Event(SubscriptionEvent::overflow("k".to_string()));
+        assert_eq!(
+            serde_json::to_string(&overflow).unwrap(),
+            r#"{"type":"event","key":"k","paths":[],"overflow":true}"#
+        );
+
+        assert_eq!(
+            serde_json::to_string(&Message::Shutdown).unwrap(),
+            r#"{"type":"shutdown"}"#
+        );
+    }
+
+    #[test]
+    fn test_event_omits_hash_when_not_requested() {
+        let event = SubscriptionEvent {
+            key: "k".to_string(),
+            paths: vec![PathBuf::from("/repo/a.rs").into()],
+            hash: None,
+            file_count: None,
+            error: None,
+            overflow: false,
+        };
+        let json = serde_json::to_string(&event).unwrap();
+        assert_eq!(json, r#"{"key":"k","paths":["/repo/a.rs"]}"#);
+    }
 }
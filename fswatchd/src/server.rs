@@ -1,191 +1,658 @@
 //! NDJSON server over Unix socket / Windows named pipe.
 
-use std::collections::HashMap;
-use std::path::PathBuf;
-use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
 use std::time::Duration;
 
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
-use tokio::sync::{RwLock, broadcast, mpsc};
-use tracing::{debug, error, info};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{RwLock, Semaphore, broadcast, mpsc, oneshot, watch};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tracing::{debug, error, info, warn};
 
-use crate::daemon::{self, DaemonState};
+use crate::daemon::{self, DaemonState, GlobKey};
+use crate::debounce::{Batch, BatchSettings, DEBOUNCE_MS, EventSpan, Pending};
+use crate::framing::{self, FrameReader};
+use crate::hasher::{self, IgnoreMatcher};
 use crate::persistence::{self, PersistedState, WatchEntry};
-use crate::protocol::{self, Request, Response, SubscriptionKey};
+use crate::protocol::{
+    self, CacheStatus, ChangedFile, DaemonStatus, EntryOrigin, FileError, Framing, HashSpec,
+    InvalidateScope, ListEntry, Message, Request, RequestEnvelope, Response, SubscriptionEvent,
+    SubscriptionKey, UnwatchTarget, WatchOptions, WatchedRoot, WirePath,
+};
 use crate::session::{RequestResult, Session, SessionBackend};
-#[cfg(windows)]
-use crate::transport::PIPE_NAME;
-#[cfg(unix)]
-use crate::transport::SOCKET_PATH;
+use crate::subscriptions::{SessionId, Subscription, Subscriptions, canonicalize_lossy};
+use crate::transport;
 
 const FLUSH_INTERVAL_SECS: u64 = 30;
-const DEBOUNCE_MS: u64 = 100;
+
+/// How long shutdown waits for connections to say goodbye
+const SHUTDOWN_GRACE_MS: u64 = 1000;
+
+/// File name prefix of sync cookies written into watched roots
+const COOKIE_PREFIX: &str = ".fswatchd-cookie-";
+
+/// How long a sync waits for the watcher to report its cookie
+const SYNC_TIMEOUT_MS: u64 = 5000;
+
+/// Default number of change events buffered for each client
+pub const DEFAULT_EVENT_BUFFER: usize = 100;
+
+/// Default number of watcher events queued for the daemon before they are dropped
+pub const DEFAULT_WATCHER_BUFFER: usize = 100;
+
+/// Default number of replies to concurrent requests queued for each client
+pub const DEFAULT_REPLY_BUFFER: usize = 100;
+
+/// Default limit of concurrent client connections
+pub const DEFAULT_MAX_CONNECTIONS: usize = 256;
+
+/// Default deadline of a request in milliseconds
+pub const DEFAULT_REQUEST_TIMEOUT_MS: u64 = 300_000;
+
+/// Default limit of subscriptions held by one client
+pub const DEFAULT_MAX_SUBSCRIPTIONS: usize = 1000;
+
+/// Default limit of requests with an `id` one client may have in flight
+pub const DEFAULT_MAX_CONCURRENT_REQUESTS: usize = 100;
+
+/// Server tunables, set from the command line
+#[derive(Debug, Clone, Copy)]
+pub struct ServerOptions {
+    /// Change events buffered for each client before it overflows
+    pub event_buffer: usize,
+    /// Watcher events queued for the daemon before they are dropped
+    pub watcher_buffer: usize,
+    /// Replies to concurrent requests queued for each client
+    pub reply_buffer: usize,
+    /// Concurrent client connections; more are refused
+    pub max_connections: usize,
+    /// Largest request line or frame in bytes
+    pub max_request_len: usize,
+    /// Deadline of hash, status, list, invalidate and since requests; 0 disables it
+    pub request_timeout_ms: u64,
+    /// Subscriptions one client may hold
+    pub max_subscriptions: usize,
+    /// Requests with an `id` one client may have in flight
+    pub max_concurrent_requests: usize,
+}
 
 /// Shared application state
 struct AppState {
+    options: ServerOptions,
+    /// One permit per connection that may be served
+    connection_slots: Arc<Semaphore>,
     daemon: RwLock<DaemonState>,
     persisted: RwLock<PersistedState>,
     dirty: AtomicBool,
     event_tx: mpsc::Sender<notify::Event>,
-    /// Broadcast channel for file changes - sends (key, paths)
-    change_tx: broadcast::Sender<(SubscriptionKey, Vec<String>)>,
-    /// Active subscriptions: key -> (root, path, glob)
-    subscriptions: RwLock<HashMap<SubscriptionKey, (PathBuf, String, String)>>,
+    /// Broadcast channel for file change events
+    change_tx: broadcast::Sender<SubscriptionEvent>,
+    /// Active subscriptions by key
+    subscriptions: RwLock<Subscriptions>,
+    /// Ignore rules per subscribed root, applied to change events
+    ignores: RwLock<HashMap<PathBuf, IgnoreMatcher>>,
+    started: Instant,
+    connected_clients: AtomicUsize,
+    /// Changed paths waiting for cache invalidation
+    pending_invalidations: AtomicUsize,
+    /// Invalidated paths waiting in subscription batches
+    pending_notifications: AtomicUsize,
+    /// Subscription events missed by lagging clients
+    lagged_events: AtomicU64,
+    /// Set once the daemon starts shutting down
+    shutdown: watch::Sender<bool>,
+    /// Sync requests waiting for their cookie file, by file name
+    cookies: Mutex<HashMap<String, oneshot::Sender<()>>>,
+    next_cookie: AtomicU64,
+    next_session: AtomicU64,
+}
+
+impl AppState {
+    fn new(
+        options: ServerOptions,
+        persisted: PersistedState,
+        event_tx: mpsc::Sender<notify::Event>,
+    ) -> Arc<Self> {
+        let (change_tx, _) = broadcast::channel::<SubscriptionEvent>(options.event_buffer);
+        Arc::new(AppState {
+            options,
+            connection_slots: Arc::new(Semaphore::new(options.max_connections)),
+            daemon: RwLock::new(DaemonState::new()),
+            persisted: RwLock::new(persisted),
+            dirty: AtomicBool::new(false),
+            event_tx,
+            change_tx,
+            subscriptions: RwLock::new(Subscriptions::default()),
+            ignores: RwLock::new(HashMap::new()),
+            started: Instant::now(),
+            connected_clients: AtomicUsize::new(0),
+            pending_invalidations: AtomicUsize::new(0),
+            pending_notifications: AtomicUsize::new(0),
+            lagged_events: AtomicU64::new(0),
+            shutdown: watch::Sender::new(false),
+            cookies: Mutex::new(HashMap::new()),
+            next_cookie: AtomicU64::new(0),
+            next_session: AtomicU64::new(0),
+        })
+    }
+
+    /// Sync requests waiting for their cookie; never held across an await
+    fn lock_cookies(&self) -> MutexGuard<'_, HashMap<String, oneshot::Sender<()>>> {
+        self.cookies.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// A sync cookie file and its waiter, both removed when the sync ends or is cancelled
+struct Cookie<'a> {
+    state: &'a AppState,
+    name: String,
+    path: PathBuf,
+}
+
+impl Drop for Cookie<'_> {
+    fn drop(&mut self) {
+        self.state.lock_cookies().remove(&self.name);
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Counts a connected client for as long as the guard lives
+struct ClientGuard<'a>(&'a AtomicUsize);
+
+impl<'a> ClientGuard<'a> {
+    fn new(counter: &'a AtomicUsize) -> Self {
+        counter.fetch_add(1, Ordering::Relaxed);
+        Self(counter)
+    }
+}
+
+impl Drop for ClientGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
 }
 
 /// Backend adapter that connects Session to AppState
 struct AppStateBackend {
     state: Arc<AppState>,
+    /// Connection the requests come from
+    session: SessionId,
 }
 
 impl SessionBackend for AppStateBackend {
     fn unwatch(
         &self,
-        key: &str,
-    ) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<(), String>> + Send + '_>> {
-        let key = key.to_string();
+        target: UnwatchTarget,
+    ) -> std::pin::Pin<
+        Box<dyn std::future::Future<Output = Result<Vec<SubscriptionKey>, String>> + Send + '_>,
+    > {
         let state = self.state.clone();
 
         Box::pin(async move {
-            // Remove from subscriptions and get the root
-            let root = {
-                let mut subs = state.subscriptions.write().await;
-                subs.remove(&key).map(|(root, _, _)| root)
+            let matches = |key: &str, root: &Path, path: &Path, glob: &str| match &target {
+                UnwatchTarget::Key { key: k } => k == key,
+                UnwatchTarget::Spec {
+                    root: r,
+                    path: p,
+                    glob: g,
+                } => **r == *root && **p == *path && g == glob,
             };
 
-            let Some(root) = root else {
-                return Ok(()); // Already removed or never existed
+            // Release this session's holds. Subscriptions other clients still hold stay,
+            // the rest are removed, remembering the affected roots.
+            let mut roots: Vec<PathBuf> = Vec::new();
+            let released: Vec<SubscriptionKey> = {
+                let mut subs = state.subscriptions.write().await;
+                let keys: Vec<SubscriptionKey> = subs
+                    .iter()
+                    .filter(|(key, sub)| matches(key, &sub.root, &sub.path, &sub.glob))
+                    .map(|(key, _)| key.clone())
+                    .collect();
+                let mut released = Vec::new();
+                for key in keys {
+                    if subs.release(&key, self.session) {
+                        released.push(key.clone());
+                    }
+                    if subs.is_unheld(&key)
+                        && let Some(sub) = subs.remove(&key)
+                    {
+                        roots.push(sub.root);
+                    }
+                }
+                released
             };
 
-            // Remove from persisted state
+            // Remove from persisted state, including entries added by persistent hashes
             {
                 let mut p = state.persisted.write().await;
                 let before = p.watch_entries.len();
                 p.watch_entries.retain(|e| {
-                    let entry_key = protocol::make_subscription_key(
-                        &e.root.to_string_lossy(),
-                        &e.path,
-                        &e.glob,
-                    );
-                    entry_key != key
+                    let keep = !matches(&e.key(), &e.root, &e.path, &e.glob);
+                    if !keep {
+                        roots.push(e.root.clone());
+                    }
+                    keep
                 });
                 if p.watch_entries.len() != before {
-                    state.dirty.store(true, std::sync::atomic::Ordering::SeqCst);
+                    state.dirty.store(true, Ordering::SeqCst);
                     if let Err(e) = persistence::save(&p) {
                         error!("Failed to save state: {}", e);
                     }
                 }
             }
 
-            // Check if any other subscriptions still use this root
-            let has_other_subscriptions = {
-                let subs = state.subscriptions.read().await;
-                subs.values().any(|(r, _, _)| r == &root)
-            };
-
-            // Also check persisted state
-            let has_persisted = {
-                let p = state.persisted.read().await;
-                p.watch_entries.iter().any(|e| e.root == root)
-            };
-
-            // Stop watcher if no more subscriptions for this root
-            if !has_other_subscriptions && !has_persisted {
-                let mut daemon = state.daemon.write().await;
-                daemon::stop_watching(&mut daemon, &root);
-            }
-
-            Ok(())
+            release_roots(&state, roots).await;
+            Ok(released)
         })
     }
 
     fn hash(
         &self,
-        root: &str,
-        path: &str,
+        root: &Path,
+        path: &Path,
         glob: &str,
         persistent: bool,
     ) -> std::pin::Pin<
         Box<dyn std::future::Future<Output = Result<(String, usize), String>> + Send + '_>,
     > {
-        let root = root.to_string();
-        let path = path.to_string();
+        let root_path = root.to_path_buf();
+        let path = path.to_path_buf();
         let glob = glob.to_string();
         let state = self.state.clone();
 
         Box::pin(async move {
-            let root_path = PathBuf::from(&root);
-
             // If persistent, add to persisted watch entries
             if persistent {
-                let entry = WatchEntry {
-                    root: root_path.clone(),
-                    path: path.clone(),
-                    glob: glob.clone(),
-                };
-                let mut p = state.persisted.write().await;
-                if p.watch_entries.insert(entry) {
-                    state.dirty.store(true, Ordering::SeqCst);
-                    if let Err(e) = persistence::save(&p) {
-                        error!("Failed to save state: {}", e);
-                    }
-                }
+                let entry = WatchEntry::new(
+                    root_path.clone(),
+                    path.clone(),
+                    glob.clone(),
+                    WatchOptions::default(),
+                    EntryOrigin::HashPersistent,
+                );
+                persist_entries(&state, vec![entry]).await;
             }
 
-            let mut daemon = state.daemon.write().await;
             match daemon::hash(
-                &mut daemon,
+                &state.daemon,
                 &root_path,
                 &path,
                 &glob,
                 persistent,
                 Some(state.event_tx.clone()),
-            ) {
+            )
+            .await
+            {
                 Ok(result) => Ok((format!("{:016x}", result.hash), result.file_count)),
                 Err(e) => Err(e.to_string()),
             }
         })
     }
 
-    fn watch(
+    fn hash_many(
         &self,
-        root: &str,
-        path: &str,
-        glob: &str,
+        specs: Vec<HashSpec>,
+    ) -> std::pin::Pin<
+        Box<dyn std::future::Future<Output = Vec<Result<(String, usize), String>>> + Send + '_>,
+    > {
+        let state = self.state.clone();
+
+        Box::pin(async move {
+            let specs: Vec<(GlobKey, bool)> = specs
+                .into_iter()
+                .map(|spec| {
+                    let key = GlobKey {
+                        root: spec.root.0,
+                        path: spec.path.0,
+                        glob: spec.glob,
+                    };
+                    (key, spec.persistent)
+                })
+                .collect();
+
+            // Add persistent specs to persisted watch entries
+            let entries: Vec<WatchEntry> = specs
+                .iter()
+                .filter(|(_, persistent)| *persistent)
+                .map(|(key, _)| {
+                    WatchEntry::new(
+                        key.root.clone(),
+                        key.path.clone(),
+                        key.glob.clone(),
+                        WatchOptions::default(),
+                        EntryOrigin::HashPersistent,
+                    )
+                })
+                .collect();
+            persist_entries(&state, entries).await;
+
+            daemon::hash_many(&state.daemon, &specs, Some(state.event_tx.clone()))
+                .await
+                .into_iter()
+                .map(|result| match result {
+                    Ok(result) => Ok((format!("{:016x}", result.hash), result.file_count)),
+                    Err(e) => Err(e.to_string()),
+                })
+                .collect()
+        })
+    }
+
+    fn sync(
+        &self,
+        root: &Path,
     ) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<(), String>> + Send + '_>> {
-        let root = root.to_string();
-        let path = path.to_string();
+        let root = root.to_path_buf();
+        let state = self.state.clone();
+
+        Box::pin(async move { sync_root(&state, &root).await })
+    }
+
+    fn hash_files(
+        &self,
+        root: &Path,
+        files: Vec<PathBuf>,
+    ) -> std::pin::Pin<
+        Box<
+            dyn std::future::Future<Output = Result<(String, usize, Vec<FileError>), String>>
+                + Send
+                + '_,
+        >,
+    > {
+        let root = root.to_path_buf();
+        let state = self.state.clone();
+
+        Box::pin(async move {
+            match daemon::hash_files(&state.daemon, &root, &files, Some(state.event_tx.clone()))
+                .await
+            {
+                Ok((result, failed)) => {
+                    let missing = failed
+                        .into_iter()
+                        .map(|(path, e)| FileError {
+                            path: path.into(),
+                            error: e.to_string(),
+                        })
+                        .collect();
+                    Ok((format!("{:016x}", result.hash), result.file_count, missing))
+                }
+                Err(e) => Err(e.to_string()),
+            }
+        })
+    }
+
+    fn clock(
+        &self,
+        root: &Path,
+    ) -> std::pin::Pin<Box<dyn std::future::Future<Output = Option<String>> + Send + '_>> {
+        let root = root.to_path_buf();
+        let state = self.state.clone();
+
+        Box::pin(async move { daemon::clock(&*state.daemon.read().await, &root) })
+    }
+
+    fn since(
+        &self,
+        root: &Path,
+        path: &Path,
+        glob: &str,
+        clock: &str,
+    ) -> std::pin::Pin<
+        Box<
+            dyn std::future::Future<Output = Result<(String, Option<Vec<ChangedFile>>), String>>
+                + Send
+                + '_,
+        >,
+    > {
+        let root = root.to_path_buf();
+        let dir = root.join(path);
         let glob = glob.to_string();
+        let token = clock.to_string();
         let state = self.state.clone();
 
         Box::pin(async move {
-            let root_path = PathBuf::from(&root);
+            let matcher = globset::Glob::new(&glob)
+                .map_err(|e| e.to_string())?
+                .compile_matcher();
+
+            let (clock, changed) = {
+                let daemon = state.daemon.read().await;
+                let changed = daemon::changed_since(&daemon, &root, &token)?;
+                (daemon::clock(&daemon, &root).unwrap_or_default(), changed)
+            };
+            let Some(changed) = changed else {
+                return Ok((clock, None));
+            };
 
-            // Start watching if not already
-            {
+            // Apply the same glob, hidden and ignore rules as hashing
+            ensure_ignore_rules(&state, &root).await;
+            let ignores = state.ignores.read().await;
+            let mut files: Vec<ChangedFile> = changed
+                .into_iter()
+                .filter(|changed| {
+                    changed.strip_prefix(&dir).is_ok_and(|rel_path| {
+                        !hasher::is_hidden(rel_path) && matcher.is_match(rel_path)
+                    })
+                })
+                .filter(|changed| !changed.is_dir())
+                .filter(|changed| {
+                    // Ignore rules hold canonical directories
+                    ignores.get(&root).is_none_or(|ignore| {
+                        !ignore.is_ignored(&canonicalize_lossy(changed), false)
+                    })
+                })
+                .map(|changed| ChangedFile {
+                    exists: changed.exists(),
+                    path: changed.into(),
+                })
+                .collect();
+            files.sort_by(|a, b| a.path.cmp(&b.path));
+
+            Ok((clock, Some(files)))
+        })
+    }
+
+    fn status(
+        &self,
+    ) -> std::pin::Pin<Box<dyn std::future::Future<Output = DaemonStatus> + Send + '_>> {
+        let state = self.state.clone();
+
+        Box::pin(async move {
+            let (roots, file_cache, result_cache, dropped_events) = {
+                let daemon = state.daemon.read().await;
+                let backend = DaemonState::watcher_backend();
+                let mut roots: Vec<WatchedRoot> = daemon
+                    .root_watchers
+                    .keys()
+                    .map(|root| WatchedRoot {
+                        root: root.clone().into(),
+                        backend: backend.clone(),
+                    })
+                    .collect();
+                roots.sort_by(|a, b| a.root.cmp(&b.root));
+
+                let file_cache = CacheStatus {
+                    entries: daemon.file_cache.len(),
+                    hits: daemon.stats.file_hits,
+                    misses: daemon.stats.file_misses,
+                };
+                let result_cache = CacheStatus {
+                    entries: daemon.result_cache.len(),
+                    hits: daemon.stats.result_hits,
+                    misses: daemon.stats.result_misses,
+                };
+                let dropped_events = daemon.dropped_events.load(Ordering::Relaxed);
+                (roots, file_cache, result_cache, dropped_events)
+            };
+
+            DaemonStatus {
+                version: env!("CARGO_PKG_VERSION").to_string(),
+                uptime_secs: state.started.elapsed().as_secs(),
+                roots,
+                subscriptions: state.subscriptions.read().await.len(),
+                persisted_entries: state.persisted.read().await.watch_entries.len(),
+                connected_clients: state.connected_clients.load(Ordering::Relaxed),
+                file_cache,
+                result_cache,
+                pending_invalidations: state.pending_invalidations.load(Ordering::Relaxed),
+                pending_notifications: state.pending_notifications.load(Ordering::Relaxed),
+                dropped_events,
+                lagged_events: state.lagged_events.load(Ordering::Relaxed),
+            }
+        })
+    }
+
+    fn list(
+        &self,
+    ) -> std::pin::Pin<
+        Box<dyn std::future::Future<Output = (Vec<ListEntry>, Vec<ListEntry>)> + Send + '_>,
+    > {
+        let state = self.state.clone();
+
+        Box::pin(async move {
+            let mut subscriptions: Vec<ListEntry> = {
+                let subs = state.subscriptions.read().await;
+                subs.iter()
+                    .map(|(key, sub)| ListEntry {
+                        key: key.clone(),
+                        root: sub.root.clone().into(),
+                        path: sub.path.clone().into(),
+                        glob: sub.glob.clone(),
+                        options: sub.options.clone(),
+                        origin: sub.origin,
+                        created_at: sub.created_at,
+                        last_access: sub.last_access,
+                        clients: Some(sub.holders.len()),
+                    })
+                    .collect()
+            };
+            let mut persisted: Vec<ListEntry> = {
+                let p = state.persisted.read().await;
+                p.watch_entries
+                    .iter()
+                    .map(|entry| ListEntry {
+                        key: entry.key(),
+                        root: entry.root.clone().into(),
+                        path: entry.path.clone().into(),
+                        glob: entry.glob.clone(),
+                        options: entry.options.clone(),
+                        origin: entry.origin,
+                        created_at: entry.created_at,
+                        last_access: entry.last_access,
+                        clients: None,
+                    })
+                    .collect()
+            };
+
+            subscriptions.sort_by_key(|entry| entry.created_at);
+            persisted.sort_by_key(|entry| entry.created_at);
+            (subscriptions, persisted)
+        })
+    }
+
+    fn invalidate(
+        &self,
+        scope: InvalidateScope,
+        rehash: bool,
+    ) -> std::pin::Pin<
+        Box<dyn std::future::Future<Output = Result<(usize, usize), String>> + Send + '_>,
+    > {
+        let state = self.state.clone();
+
+        Box::pin(async move {
+            // Resolve the scope to a directory, plus the spec a key refers to
+            let (dir, spec) = match scope {
+                InvalidateScope::Root { root, path } => {
+                    let dir = match path {
+                        Some(path) => root.join(path),
+                        None => root.0,
+                    };
+                    (dir, None)
+                }
+                InvalidateScope::Key { key } => {
+                    let Some(spec) = lookup_key(&state, &key).await else {
+                        return Err(format!("Unknown subscription key: {}", key));
+                    };
+                    (spec.root.join(&spec.path), Some(spec))
+                }
+            };
+
+            let (files, mut keys) = {
                 let mut daemon = state.daemon.write().await;
-                if let Err(e) =
-                    daemon::ensure_watching(&mut daemon, &root_path, Some(state.event_tx.clone()))
+                daemon::invalidate_dir(&mut daemon, &dir)
+            };
+            let results = keys.len();
+
+            if rehash {
+                if let Some(spec) = spec
+                    && !keys.contains(&spec)
                 {
-                    return Err(e.to_string());
+                    keys.push(spec);
+                }
+                for key in keys {
+                    spawn_rehash(&state, key);
                 }
             }
 
-            // Add to persisted watch entries
+            Ok((files, results))
+        })
+    }
+
+    fn watch(
+        &self,
+        root: &Path,
+        path: &Path,
+        glob: &str,
+        options: &WatchOptions,
+        durable: bool,
+    ) -> std::pin::Pin<Box<dyn std::future::Future<Output = Result<(), String>> + Send + '_>> {
+        let root_path = root.to_path_buf();
+        let path = path.to_path_buf();
+        let glob = glob.to_string();
+        let options = options.clone();
+        let state = self.state.clone();
+
+        Box::pin(async move {
+            let key = protocol::make_watch_key(&root_path, &path, &glob, &options);
+
+            // Start the watcher and register the held subscription under one subscriptions
+            // lock, so `release_roots` cannot stop the watcher in between. Ignore rules are
+            // built before taking it.
+            ensure_ignore_rules(&state, &root_path).await;
             {
-                let entry = WatchEntry {
-                    root: root_path.clone(),
-                    path: path.clone(),
-                    glob: glob.clone(),
-                };
-                let mut p = state.persisted.write().await;
-                if p.watch_entries.insert(entry) {
-                    state.dirty.store(true, Ordering::SeqCst);
-                    if let Err(e) = persistence::save(&p) {
-                        error!("Failed to save state: {}", e);
+                let mut subs = state.subscriptions.write().await;
+                {
+                    let mut daemon = state.daemon.write().await;
+                    if let Err(e) = daemon::ensure_watching(
+                        &mut daemon,
+                        &root_path,
+                        Some(state.event_tx.clone()),
+                    ) {
+                        return Err(e.to_string());
                     }
                 }
+                subs.insert(
+                    key.clone(),
+                    Subscription::new(
+                        root_path.clone(),
+                        path.clone(),
+                        glob.clone(),
+                        options.clone(),
+                        EntryOrigin::Watch,
+                    ),
+                );
+                subs.hold(&key, self.session);
+            }
+            // Another subscription of the root may have been released before ours was
+            // registered, taking the rules along
+            ensure_ignore_rules(&state, &root_path).await;
+
+            // Only durable watches outlive the connection
+            if durable {
+                let entry = WatchEntry::new(root_path, path, glob, options, EntryOrigin::Watch);
+                persist_entries(&state, vec![entry]).await;
             }
 
             Ok(())
@@ -194,11 +661,28 @@ impl SessionBackend for AppStateBackend {
 }
 
 #[tokio::main]
-pub async fn run(socket_path: Option<String>) -> Result<(), Box<dyn std::error::Error>> {
+pub async fn run(
+    socket_path: Option<String>,
+    options: ServerOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    for (name, value) in [
+        ("event buffer", options.event_buffer),
+        ("watcher buffer", options.watcher_buffer),
+        ("reply buffer", options.reply_buffer),
+        ("connection limit", options.max_connections),
+        ("request size limit", options.max_request_len),
+        ("subscription limit", options.max_subscriptions),
+        ("concurrent request limit", options.max_concurrent_requests),
+    ] {
+        if value == 0 {
+            return Err(format!("The {} must be at least 1", name).into());
+        }
+    }
+
     #[cfg(unix)]
-    let socket_path = socket_path.unwrap_or_else(|| SOCKET_PATH.to_string());
+    let socket_path = socket_path.unwrap_or_else(transport::default_socket_path);
     #[cfg(windows)]
-    let socket_path = socket_path.unwrap_or_else(|| PIPE_NAME.to_string());
+    let socket_path = socket_path.unwrap_or_else(transport::default_pipe_name);
 
     // Check if another daemon is already running
     #[cfg(unix)]
@@ -209,17 +693,8 @@ pub async fn run(socket_path: Option<String>) -> Result<(), Box<dyn std::error::
         let _ = std::fs::remove_file(&socket_path);
     }
 
-    let (event_tx, mut event_rx) = mpsc::channel::<notify::Event>(100);
-    let (change_tx, _) = broadcast::channel::<(SubscriptionKey, Vec<String>)>(100);
-
-    let state = Arc::new(AppState {
-        daemon: RwLock::new(DaemonState::new()),
-        persisted: RwLock::new(persistence::load()),
-        dirty: AtomicBool::new(false),
-        event_tx,
-        change_tx: change_tx.clone(),
-        subscriptions: RwLock::new(HashMap::new()),
-    });
+    let (event_tx, mut event_rx) = mpsc::channel::<notify::Event>(options.watcher_buffer);
+    let state = AppState::new(options, persistence::load(), event_tx);
 
     // Restore watchers from persisted state
     restore_watchers(&state).await;
@@ -227,58 +702,77 @@ pub async fn run(socket_path: Option<String>) -> Result<(), Box<dyn std::error::
     // Handle file change events from notify
     let state_clone = state.clone();
     tokio::spawn(async move {
-        let mut pending: HashMap<PathBuf, tokio::time::Instant> = HashMap::new();
-        let mut interval = tokio::time::interval(Duration::from_millis(DEBOUNCE_MS));
+        // Changed paths waiting for cache invalidation
+        let mut pending = Pending::default();
+        // Invalidated paths waiting to be reported, batched per subscription
+        let mut batches: HashMap<SubscriptionKey, Batch> = HashMap::new();
+        // Events waiting for their aggregate hash, the latest per subscription
+        let mut hashing: HashMap<SubscriptionKey, JoinHandle<()>> = HashMap::new();
+        let mut delay = invalidation_delay(&state_clone).await;
 
         loop {
+            state_clone
+                .pending_invalidations
+                .store(pending.len(), Ordering::Relaxed);
+            state_clone
+                .pending_notifications
+                .store(batches.values().map(Batch::len).sum(), Ordering::Relaxed);
+
+            let wake = pending
+                .next_deadline(delay)
+                .into_iter()
+                .chain(batches.values().filter_map(Batch::next_deadline))
+                .min();
+
             tokio::select! {
                 Some(event) = event_rx.recv() => {
                     use notify::EventKind;
+                    report_overflow(&state_clone, &mut hashing).await;
                     match event.kind {
                         EventKind::Modify(_) | EventKind::Create(_) | EventKind::Remove(_) => {
-                            let deadline = tokio::time::Instant::now() + Duration::from_millis(DEBOUNCE_MS);
+                            let now = Instant::now();
+                            let mut cookies = Vec::new();
                             for path in event.paths {
-                                pending.insert(path, deadline);
+                                match cookie_name(&path) {
+                                    Some(name) => cookies.push(name.to_string()),
+                                    None => {
+                                        let max_delay = if pending.contains(&path) {
+                                            None
+                                        } else {
+                                            max_delay_for(&state_clone, &path).await
+                                        };
+                                        pending.add(path, now, max_delay);
+                                    }
+                                }
+                            }
+
+                            if !cookies.is_empty() {
+                                // Changes reported before the cookie skip the debounce,
+                                // so the waiting request sees them
+                                let ready = pending.drain();
+                                if !ready.is_empty() {
+                                    invalidate_changes(&state_clone, &ready, &mut batches).await;
+                                }
+                                let mut waiting = state_clone.lock_cookies();
+                                for name in cookies {
+                                    if let Some(tx) = waiting.remove(&name) {
+                                        let _ = tx.send(());
+                                    }
+                                }
                             }
+                            delay = invalidation_delay(&state_clone).await;
                         }
                         _ => {}
                     }
                 }
-                _ = interval.tick() => {
-                    let now = tokio::time::Instant::now();
-                    let ready: Vec<PathBuf> = pending
-                        .iter()
-                        .filter(|(_, deadline)| now >= **deadline)
-                        .map(|(path, _)| path.clone())
-                        .collect();
-
+                _ = tokio::time::sleep_until(wake.unwrap_or_else(Instant::now)), if wake.is_some() => {
+                    let now = Instant::now();
+                    let ready = pending.take_ready(now, delay);
                     if !ready.is_empty() {
-                        for path in &ready {
-                            pending.remove(path);
-                            // Invalidate cache
-                            let mut daemon = state_clone.daemon.write().await;
-                            daemon::invalidate_file(&mut daemon, path);
-                        }
-
-                        // Check which subscriptions match and notify
-                        let subs = state_clone.subscriptions.read().await;
-                        let mut matches: HashMap<SubscriptionKey, Vec<String>> = HashMap::new();
-
-                        for (key, (root, path, glob)) in subs.iter() {
-                            for changed_path in &ready {
-                                if matches_watch(changed_path, root, path, glob) {
-                                    matches
-                                        .entry(key.clone())
-                                        .or_default()
-                                        .push(changed_path.to_string_lossy().to_string());
-                                }
-                            }
-                        }
-
-                        for (key, paths) in matches {
-                            let _ = state_clone.change_tx.send((key, paths));
-                        }
+                        invalidate_changes(&state_clone, &ready, &mut batches).await;
                     }
+
+                    flush_batches(&state_clone, &mut batches, &mut hashing, now).await;
                 }
             }
         }
@@ -299,8 +793,65 @@ pub async fn run(socket_path: Option<String>) -> Result<(), Box<dyn std::error::
         }
     });
 
-    // Start accepting connections
-    accept_connections(state, &socket_path).await
+    // Accept connections until a shutdown command or signal
+    tokio::select! {
+        result = accept_connections(state.clone(), &socket_path) => result?,
+        _ = shutdown_requested(&state) => {}
+    }
+
+    shutdown(&state).await;
+    #[cfg(unix)]
+    let _ = std::fs::remove_file(&socket_path);
+    info!("Daemon stopped");
+    Ok(())
+}
+
+/// Wait for a `shutdown` command, SIGINT or SIGTERM
+async fn shutdown_requested(state: &Arc<AppState>) {
+    let mut shutdown_rx = state.shutdown.subscribe();
+    let command = shutdown_rx.wait_for(|stop| *stop);
+
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{SignalKind, signal};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(e) => {
+                error!("Failed to install SIGTERM handler: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = command => info!("Shutdown requested by client"),
+        _ = tokio::signal::ctrl_c() => info!("Received SIGINT, shutting down"),
+        _ = terminate => info!("Received SIGTERM, shutting down"),
+    }
+}
+
+/// Notify clients, save dirty state and drop the watchers
+async fn shutdown(state: &Arc<AppState>) {
+    state.shutdown.send_replace(true);
+
+    // Give connections a moment to send the shutdown notice and close
+    let deadline = Instant::now() + Duration::from_millis(SHUTDOWN_GRACE_MS);
+    while state.connected_clients.load(Ordering::Relaxed) > 0 && Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    if state.dirty.swap(false, Ordering::SeqCst) {
+        let persisted = state.persisted.read().await;
+        if let Err(e) = persistence::save(&persisted) {
+            error!("Failed to save state: {}", e);
+        }
+    }
+
+    state.daemon.write().await.root_watchers.clear();
 }
 
 #[cfg(unix)]
@@ -308,16 +859,21 @@ async fn accept_connections(
     state: Arc<AppState>,
     socket_path: &str,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let listener = tokio::net::UnixListener::bind(socket_path)?;
+    let listener = transport::bind(socket_path)?;
     info!("Daemon started, listening on {}", socket_path);
 
     loop {
         let (stream, _) = listener.accept().await?;
+        let Ok(slot) = state.connection_slots.clone().try_acquire_owned() else {
+            tokio::spawn(refuse_connection(stream, state.options.max_connections));
+            continue;
+        };
         let state = state.clone();
         tokio::spawn(async move {
             if let Err(e) = handle_connection(state, stream).await {
                 debug!("Connection closed: {}", e);
             }
+            drop(slot);
         });
     }
 }
@@ -344,15 +900,36 @@ async fn accept_connections(
             .first_pipe_instance(false)
             .create(pipe_name)?;
 
+        let Ok(slot) = state.connection_slots.clone().try_acquire_owned() else {
+            tokio::spawn(refuse_connection(stream, state.options.max_connections));
+            continue;
+        };
         let state = state.clone();
         tokio::spawn(async move {
             if let Err(e) = handle_connection(state, stream).await {
                 debug!("Connection closed: {}", e);
             }
+            drop(slot);
         });
     }
 }
 
+/// Tell a client over the connection limit why it is disconnected
+async fn refuse_connection<S>(mut stream: S, limit: usize)
+where
+    S: tokio::io::AsyncWrite + Unpin,
+{
+    debug!(limit, "refusing connection over the limit");
+    let error = Message::Error {
+        id: None,
+        error: format!("Too many connections (limit {})", limit),
+    };
+    if let Ok(frame) = framing::encode(Framing::Ndjson, &error) {
+        let _ = stream.write_all(&frame).await;
+        let _ = stream.shutdown().await;
+    }
+}
+
 /// Handle a single client connection
 async fn handle_connection<S>(
     state: Arc<AppState>,
@@ -361,89 +938,638 @@ async fn handle_connection<S>(
 where
     S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send,
 {
+    let _client = ClientGuard::new(&state.connected_clients);
+
     let (reader, mut writer) = tokio::io::split(stream);
-    let mut reader = BufReader::new(reader);
-    let mut line = String::new();
+    let mut reader = FrameReader::new(reader, state.options.max_request_len);
+    let mut framing = Framing::Ndjson;
 
     // Subscribe to change events for this connection
     let mut change_rx = state.change_tx.subscribe();
 
     // Create session for this connection
     let mut session = Session::new();
+    session.set_max_subscriptions(state.options.max_subscriptions);
 
     // Create backend adapter
+    let session_id = state.next_session.fetch_add(1, Ordering::Relaxed);
     let backend = AppStateBackend {
         state: state.clone(),
+        session: session_id,
     };
 
-    loop {
-        line.clear();
-
-        // Wait for either a request or timeout (to check for events)
-        let read_result =
-            tokio::time::timeout(Duration::from_millis(50), reader.read_line(&mut line)).await;
-
-        match read_result {
-            Ok(Ok(0)) => break, // Connection closed
-            Ok(Ok(_)) => {
-                // Got a request - parse and process
-                let response = match serde_json::from_str::<Request>(&line) {
-                    Ok(req) => {
-                        let result = session.process_request(req, &backend).await;
-                        match result {
-                            RequestResult::Response(resp) => resp,
-                            RequestResult::Subscribe { response, key } => {
-                                // Add to global subscriptions
-                                register_subscription(&state, &key, &line).await;
-                                response
+    // Replies to requests processed concurrently, written in completion order
+    let (reply_tx, mut reply_rx) = mpsc::channel::<Message>(state.options.reply_buffer);
+    let request_slots = Arc::new(Semaphore::new(state.options.max_concurrent_requests));
+
+    let mut shutdown_rx = state.shutdown.subscribe();
+
+    // Wait for whichever comes first: a request, a concurrent reply, an event or shutdown.
+    // Every branch is cancel-safe, so nothing is lost when another one wins.
+    let result: Result<(), Box<dyn std::error::Error + Send + Sync>> = async {
+        loop {
+            tokio::select! {
+                frame = reader.next_frame() => {
+                    let frame = match frame {
+                        Ok(Some(frame)) => frame,
+                        Ok(None) => break, // Connection closed
+                        Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+                            // Oversized request: the rest of the stream cannot be framed
+                            let error = Message::Error { id: None, error: e.to_string() };
+                            write_message(&mut writer, framing, &error).await?;
+                            writer.flush().await?;
+                            break;
+                        }
+                        Err(e) => return Err(e.into()),
+                    };
+
+                    // Got a request - parse and process
+                    let reply = match framing::decode_request(framing, &frame) {
+                        Ok(RequestEnvelope {
+                            id: Some(id),
+                            request,
+                        }) if request.is_stateless() => {
+                            // Requests with an ID may be answered out of order
+                            match request_slots.clone().try_acquire_owned() {
+                                Ok(permit) => {
+                                    let backend = AppStateBackend {
+                                        state: state.clone(),
+                                        session: session_id,
+                                    };
+                                    let reply_tx = reply_tx.clone();
+                                    tokio::spawn(async move {
+                                        let response = process_stateless(request, &backend).await;
+                                        drop(permit);
+                                        let _ = reply_tx
+                                            .send(Message::reply(Some(id), response))
+                                            .await;
+                                    });
+                                    None
+                                }
+                                Err(_) => Some(Message::reply(
+                                    Some(id),
+                                    Response::Error {
+                                        error: format!(
+                                            "Too many concurrent requests (limit {}); wait for replies first",
+                                            state.options.max_concurrent_requests
+                                        ),
+                                    },
+                                )),
                             }
-                            RequestResult::Unsubscribe { response } => response,
                         }
+                        Ok(RequestEnvelope { id, request }) if request.is_stateless() => {
+                            Some(Message::reply(id, process_stateless(request, &backend).await))
+                        }
+                        Ok(RequestEnvelope { id, request }) => {
+                            let result = session.process_request(request, &backend).await;
+                            let response = match result {
+                                RequestResult::Response(resp) => resp,
+                                RequestResult::Subscribe { response, key } => {
+                                    debug!(%key, session = session_id, "subscribed");
+                                    response
+                                }
+                                RequestResult::Unsubscribe { response } => response,
+                                RequestResult::Shutdown { response } => {
+                                    state.shutdown.send_replace(true);
+                                    response
+                                }
+                            };
+                            Some(Message::reply(id, response))
+                        }
+                        Err((id, error)) => Some(Message::Error { id, error }),
+                    };
+
+                    if let Some(reply) = reply {
+                        write_message(&mut writer, framing, &reply).await?;
+                        writer.flush().await?;
                     }
-                    Err(e) => Response::Error {
-                        error: format!("Invalid request: {}", e),
-                    },
-                };
 
-                let response_json = serde_json::to_string(&response)?;
-                writer.write_all(response_json.as_bytes()).await?;
-                writer.write_all(b"\n").await?;
-                writer.flush().await?;
+                    // A `hello` may switch framing for everything after its response
+                    if session.framing() != framing {
+                        framing = session.framing();
+                        reader.set_framing(framing);
+                    }
+                }
+                Some(reply) = reply_rx.recv() => {
+                    write_message(&mut writer, framing, &reply).await?;
+                    writer.flush().await?;
+                }
+                event = change_rx.recv() => match event {
+                    Ok(event) => {
+                        if session.should_receive_event(&event.key) {
+                            write_message(&mut writer, framing, &Message::Event(event)).await?;
+                            writer.flush().await?;
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        // Missed events may have been for any of this client's keys, so
+                        // tell it to resync all of them
+                        state.lagged_events.fetch_add(missed, Ordering::Relaxed);
+                        debug!(missed, "client lagged behind, sending overflow");
+                        for key in session.subscriptions() {
+                            let overflow = SubscriptionEvent::overflow(key.clone());
+                            write_message(&mut writer, framing, &Message::Event(overflow)).await?;
+                        }
+                        writer.flush().await?;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                },
+                _ = async { shutdown_rx.wait_for(|stop| *stop).await.is_ok() } => {
+                    write_message(&mut writer, framing, &Message::Shutdown).await?;
+                    writer.flush().await?;
+                    break;
+                }
             }
-            Ok(Err(e)) => return Err(e.into()), // Read error
-            Err(_) => {}                        // Timeout - no request, check for events below
         }
+        Ok(())
+    }
+    .await;
+
+    // Subscriptions of this session end with it, unless durable or held by another client
+    release_subscriptions(&state, session_id, session.subscriptions()).await;
+
+    debug!(protocol = session.protocol_version(), "client disconnected");
+    result
+}
+
+/// Process a stateless request, failing it once it exceeds the request timeout
+async fn process_stateless(request: Request, backend: &AppStateBackend) -> Response {
+    let timeout_ms = backend.state.options.request_timeout_ms;
+    let response = Session::process_stateless(request, backend);
+    if timeout_ms == 0 {
+        return response.await;
+    }
+    match tokio::time::timeout(Duration::from_millis(timeout_ms), response).await {
+        Ok(response) => response,
+        Err(_) => Response::Error {
+            error: format!("Request timed out after {} ms", timeout_ms),
+        },
+    }
+}
+
+/// Write one message frame
+async fn write_message<W>(
+    writer: &mut W,
+    framing: Framing,
+    message: &Message,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+where
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    writer
+        .write_all(&framing::encode(framing, message)?)
+        .await?;
+    Ok(())
+}
+
+/// Delay before invalidating a changed path: the default debounce, or less if a
+/// subscription asked for faster events.
+async fn invalidation_delay(state: &Arc<AppState>) -> Duration {
+    let subs = state.subscriptions.read().await;
+    subs.values()
+        .map(|sub| BatchSettings::from(&sub.options).debounce)
+        .chain(std::iter::once(Duration::from_millis(DEBOUNCE_MS)))
+        .min()
+        .unwrap_or_default()
+}
+
+/// Smallest `max_delay` among the subscriptions matching a changed path
+async fn max_delay_for(state: &Arc<AppState>, path: &Path) -> Option<Duration> {
+    let subs = state.subscriptions.read().await;
+    let ignores = state.ignores.read().await;
+    subs.matching(path, &ignores)
+        .into_iter()
+        .filter_map(|key| subs.get(key))
+        .filter_map(|sub| BatchSettings::from(&sub.options).max_delay)
+        .min()
+}
+
+/// Invalidate caches for changed paths and queue them on matching subscriptions' batches
+async fn invalidate_changes(
+    state: &Arc<AppState>,
+    ready: &[(PathBuf, EventSpan)],
+    batches: &mut HashMap<SubscriptionKey, Batch>,
+) {
+    // Reload ignore rules of roots whose ignore files changed, building them unlocked
+    let reload: Vec<PathBuf> = state
+        .ignores
+        .read()
+        .await
+        .keys()
+        .filter(|root| {
+            ready
+                .iter()
+                .any(|(path, _)| hasher::is_ignore_file(path) && path.starts_with(root))
+        })
+        .cloned()
+        .collect();
+    for root in reload {
+        debug!(root = %root.display(), "reloading ignore rules");
+        let rebuilt = build_ignore_rules(root.clone()).await;
+        // Skip roots released in the meantime
+        if let Some(ignore) = state.ignores.write().await.get_mut(&root) {
+            *ignore = rebuilt;
+        }
+    }
 
-        // Drain any pending events (non-blocking)
-        loop {
-            match change_rx.try_recv() {
-                Ok((key, paths)) => {
-                    if session.should_receive_event(&key) {
-                        let event = protocol::SubscriptionEvent { key, paths };
-                        let event_json = serde_json::to_string(&event)?;
-                        writer.write_all(event_json.as_bytes()).await?;
-                        writer.write_all(b"\n").await?;
-                    }
+    // Map each changed path to its matching subscriptions in one pass, noting paths
+    // watched by a content-only subscription
+    let matched: Vec<_> = {
+        let subs = state.subscriptions.read().await;
+        let ignores = state.ignores.read().await;
+        ready
+            .iter()
+            .map(|(path, span)| {
+                let keys: Vec<SubscriptionKey> =
+                    subs.matching(path, &ignores).into_iter().cloned().collect();
+                let content_only = keys
+                    .iter()
+                    .any(|key| subs.get(key).is_some_and(|sub| sub.options.content_only));
+                (path, *span, keys, content_only)
+            })
+            .collect()
+    };
+
+    // Content-only paths are rehashed without holding any lock, so identical rewrites
+    // keep their cache entries
+    let rehashed = daemon::rehash_files(
+        &state.daemon,
+        matched
+            .iter()
+            .filter(|(_, _, _, content_only)| *content_only)
+            .map(|(path, _, _, _)| (*path).clone())
+            .collect(),
+    )
+    .await;
+
+    let subs = state.subscriptions.read().await;
+    let mut daemon = state.daemon.write().await;
+    let ignores = state.ignores.read().await;
+    let paths: Vec<&PathBuf> = ready.iter().map(|(path, _)| path).collect();
+    daemon::advance_clock(&mut daemon, &paths);
+
+    for (path, span, keys, _) in matched {
+        let unchanged = match rehashed.get(path) {
+            Some(rehashed) => !daemon::refresh_file(&mut daemon, path, rehashed),
+            None => {
+                // Hidden and ignored files, e.g. under `.git` or `target`, are never
+                // walked, so they must not discard hashes computed concurrently
+                daemon::invalidate_file(&mut daemon, path, |root| {
+                    walks_include(&ignores, root, path)
+                });
+                false
+            }
+        };
+
+        // Queue the path on each matching subscription's batch
+        for key in &keys {
+            let Some(sub) = subs.get(key) else {
+                continue;
+            };
+            if sub.options.content_only && unchanged {
+                continue;
+            }
+            batches
+                .entry(key.clone())
+                .or_insert_with(|| Batch::new(BatchSettings::from(&sub.options)))
+                .add(path.clone(), span);
+        }
+    }
+}
+
+/// Whether walks of `root` may include `path`, going by the hidden and ignore rules.
+/// Without ignore rules for the root, any path that is not hidden counts.
+fn walks_include(ignores: &HashMap<PathBuf, IgnoreMatcher>, root: &Path, path: &Path) -> bool {
+    let Ok(rel_path) = path.strip_prefix(root) else {
+        return true;
+    };
+    if hasher::is_hidden(rel_path) {
+        return false;
+    }
+    ignores.get(root).is_none_or(|ignore| {
+        let path = canonicalize_lossy(path);
+        !ignore.is_ignored(&path, path.is_dir())
+    })
+}
+
+/// Notify subscriptions whose batches are ready.
+///
+/// Aggregate hashes are recomputed in spawned tasks, so a cold walk does not stall the
+/// event loop. Each task sends after the previous one of its key, keeping events in order.
+async fn flush_batches(
+    state: &Arc<AppState>,
+    batches: &mut HashMap<SubscriptionKey, Batch>,
+    hashing: &mut HashMap<SubscriptionKey, JoinHandle<()>>,
+    now: Instant,
+) {
+    let mut ready_events = Vec::new();
+    {
+        let subs = state.subscriptions.read().await;
+
+        // Drop batches of subscriptions that were removed in the meantime
+        batches.retain(|key, _| subs.contains_key(key));
+
+        for (key, batch) in batches.iter_mut() {
+            let ready = batch.take_ready(now);
+            if ready.is_empty() {
+                continue;
+            }
+            let Some(sub) = subs.get(key) else {
+                continue;
+            };
+
+            let event = SubscriptionEvent {
+                key: key.clone(),
+                paths: ready.into_iter().map(WirePath::from).collect(),
+                hash: None,
+                file_count: None,
+                error: None,
+                overflow: false,
+            };
+            let spec = sub.options.include_hash.then(|| GlobKey {
+                root: sub.root.clone(),
+                path: sub.path.clone(),
+                glob: sub.glob.clone(),
+            });
+            ready_events.push((event, spec));
+        }
+    }
+
+    batches.retain(|_, batch| !batch.is_empty());
+    hashing.retain(|_, task| !task.is_finished());
+
+    for (mut event, spec) in ready_events {
+        let Some(spec) = spec else {
+            let _ = state.change_tx.send(event);
+            continue;
+        };
+
+        let previous = hashing.remove(&event.key);
+        let key = event.key.clone();
+        let state = state.clone();
+        let task = tokio::spawn(async move {
+            match daemon::hash(
+                &state.daemon,
+                &spec.root,
+                &spec.path,
+                &spec.glob,
+                false,
+                None,
+            )
+            .await
+            {
+                Ok(result) => {
+                    event.hash = Some(format!("{:016x}", result.hash));
+                    event.file_count = Some(result.file_count);
                 }
-                Err(broadcast::error::TryRecvError::Empty) => break,
-                Err(broadcast::error::TryRecvError::Lagged(_)) => continue, // Skip missed events
-                Err(broadcast::error::TryRecvError::Closed) => return Ok(()),
+                Err(e) => event.error = Some(e.to_string()),
+            }
+            if let Some(previous) = previous {
+                let _ = previous.await;
+            }
+            let _ = state.change_tx.send(event);
+        });
+        hashing.insert(key, task);
+    }
+}
+
+/// Handle watcher events dropped because the daemon's queue was full. Changes under the
+/// affected roots may have been missed, so their caches are dropped and their
+/// subscriptions are sent an overflow event.
+async fn report_overflow(
+    state: &Arc<AppState>,
+    hashing: &mut HashMap<SubscriptionKey, JoinHandle<()>>,
+) {
+    if !daemon::has_overflowed(&*state.daemon.read().await) {
+        return;
+    }
+    let roots = daemon::take_overflowed_roots(&mut *state.daemon.write().await);
+
+    for root in &roots {
+        warn!(root = %root.display(), "watcher events dropped, sending overflow");
+    }
+    let keys: Vec<SubscriptionKey> = state
+        .subscriptions
+        .read()
+        .await
+        .iter()
+        .filter(|(_, sub)| roots.contains(&sub.root))
+        .map(|(key, _)| key.clone())
+        .collect();
+    for key in keys {
+        let event = SubscriptionEvent::overflow(key.clone());
+        // Sent after the events of the key still being hashed, keeping them in order
+        match hashing.remove(&key) {
+            Some(previous) => {
+                let state = state.clone();
+                let task = tokio::spawn(async move {
+                    let _ = previous.await;
+                    let _ = state.change_tx.send(event);
+                });
+                hashing.insert(key, task);
+            }
+            None => {
+                let _ = state.change_tx.send(event);
             }
         }
-        writer.flush().await?;
     }
+}
 
-    Ok(())
+/// Add entries to the persisted state. New entries are saved right away; access time
+/// updates of existing entries wait for the periodic flush.
+async fn persist_entries(state: &Arc<AppState>, entries: Vec<WatchEntry>) {
+    if entries.is_empty() {
+        return;
+    }
+
+    let mut p = state.persisted.write().await;
+    let mut inserted = false;
+    for entry in entries {
+        inserted |= p.record(entry);
+    }
+    state.dirty.store(true, Ordering::SeqCst);
+
+    if inserted && let Err(e) = persistence::save(&p) {
+        error!("Failed to save state: {}", e);
+    }
 }
 
-/// Register a subscription in the global state
-async fn register_subscription(state: &Arc<AppState>, key: &str, request_line: &str) {
-    // Parse the request again to get root/path/glob
-    if let Ok(Request::Watch { root, path, glob }) = serde_json::from_str(request_line) {
-        let root_path = PathBuf::from(&root);
+/// Release the subscriptions of a closed session. Subscriptions no other session holds
+/// are removed unless a persisted entry keeps them.
+async fn release_subscriptions<'a>(
+    state: &Arc<AppState>,
+    session: SessionId,
+    keys: impl Iterator<Item = &'a SubscriptionKey>,
+) {
+    let persisted: HashSet<SubscriptionKey> = {
+        let p = state.persisted.read().await;
+        p.watch_entries.iter().map(|e| e.key()).collect()
+    };
+
+    let mut roots = Vec::new();
+    {
         let mut subs = state.subscriptions.write().await;
-        subs.insert(key.to_string(), (root_path, path, glob));
+        for key in keys {
+            if subs.release(key, session)
+                && subs.is_unheld(key)
+                && !persisted.contains(key)
+                && let Some(sub) = subs.remove(key)
+            {
+                debug!(key = %key, "released subscription");
+                roots.push(sub.root);
+            }
+        }
     }
+    release_roots(state, roots).await;
+}
+
+/// Drop ignore rules and stop watchers of roots that nothing uses anymore
+async fn release_roots(state: &Arc<AppState>, mut roots: Vec<PathBuf>) {
+    roots.sort();
+    roots.dedup();
+    for root in roots {
+        // Check if any other subscriptions or persisted entries still use this root. The
+        // locks are held until the watcher is stopped, so a concurrent `watch` or persistent
+        // hash cannot register in between.
+        let subs = state.subscriptions.read().await;
+        let has_other_subscriptions = subs.values().any(|sub| sub.root == root);
+        let p = state.persisted.read().await;
+        let has_persisted = p.watch_entries.iter().any(|e| e.root == root);
+
+        if !has_other_subscriptions {
+            state.ignores.write().await.remove(&root);
+        }
+
+        // Stop watcher if no more subscriptions for this root. Roots hashed by file
+        // list keep theirs.
+        if !has_other_subscriptions && !has_persisted {
+            let mut daemon = state.daemon.write().await;
+            if !daemon.watches_file_lists(&root) {
+                daemon::stop_watching(&mut daemon, &root);
+            }
+        }
+    }
+}
+
+/// Load ignore rules for a root if not already loaded. They are built without any lock
+/// held, since that walks the tree.
+async fn ensure_ignore_rules(state: &Arc<AppState>, root: &PathBuf) {
+    if state.ignores.read().await.contains_key(root) {
+        return;
+    }
+    let ignore = build_ignore_rules(root.clone()).await;
+    state
+        .ignores
+        .write()
+        .await
+        .entry(root.clone())
+        .or_insert(ignore);
+}
+
+/// Build the ignore rules of a root on the blocking pool
+async fn build_ignore_rules(root: PathBuf) -> IgnoreMatcher {
+    daemon::blocking(move || IgnoreMatcher::build(&root)).await
+}
+
+/// File name of a sync cookie, if the path is one
+fn cookie_name(path: &Path) -> Option<&str> {
+    path.file_name()?
+        .to_str()
+        .filter(|name| name.starts_with(COOKIE_PREFIX))
+}
+
+/// Write a cookie file into the watched root containing `root` and wait until the event
+/// loop sees it. Events are delivered in order, so every earlier write has been applied by then.
+async fn sync_root(state: &Arc<AppState>, root: &Path) -> Result<(), String> {
+    // Unwatched roots have no pending events to wait for
+    let watched = {
+        let daemon = state.daemon.read().await;
+        root.ancestors()
+            .find(|dir| daemon.root_watchers.contains_key(*dir))
+            .map(Path::to_path_buf)
+    };
+    let Some(root) = watched else {
+        return Ok(());
+    };
+
+    let name = format!(
+        "{}{}-{}",
+        COOKIE_PREFIX,
+        std::process::id(),
+        state.next_cookie.fetch_add(1, Ordering::Relaxed)
+    );
+    let (tx, rx) = oneshot::channel();
+    state.lock_cookies().insert(name.clone(), tx);
+    let cookie = Cookie {
+        state,
+        path: root.join(&name),
+        name,
+    };
+
+    match std::fs::write(&cookie.path, b"") {
+        Ok(()) => match tokio::time::timeout(Duration::from_millis(SYNC_TIMEOUT_MS), rx).await {
+            Ok(_) => Ok(()),
+            Err(_) => Err(format!(
+                "Timed out waiting for the watcher to sync {}",
+                root.display()
+            )),
+        },
+        Err(e) => Err(format!("Failed to write sync cookie: {}", e)),
+    }
+}
+
+/// Find the root/path/glob of a subscription or persisted entry by key
+async fn lookup_key(state: &Arc<AppState>, key: &str) -> Option<GlobKey> {
+    if let Some(sub) = state.subscriptions.read().await.get(key) {
+        return Some(GlobKey {
+            root: sub.root.clone(),
+            path: sub.path.clone(),
+            glob: sub.glob.clone(),
+        });
+    }
+
+    let p = state.persisted.read().await;
+    p.watch_entries
+        .iter()
+        .find(|entry| entry.key() == key)
+        .map(|entry| GlobKey {
+            root: entry.root.clone(),
+            path: entry.path.clone(),
+            glob: entry.glob.clone(),
+        })
+}
+
+/// Recompute a cached hash in the background
+fn spawn_rehash(state: &Arc<AppState>, key: GlobKey) {
+    let state = state.clone();
+    tokio::spawn(async move {
+        debug!(
+            "Background re-hash for: {} path={} glob={}",
+            key.root.display(),
+            key.path.display(),
+            key.glob
+        );
+        let start = std::time::Instant::now();
+        match daemon::hash(&state.daemon, &key.root, &key.path, &key.glob, false, None).await {
+            Ok(result) => {
+                info!(
+                    "Re-hash complete: {} path={} files={} duration={:?}",
+                    key.root.display(),
+                    key.path.display(),
+                    result.file_count,
+                    start.elapsed()
+                );
+            }
+            Err(e) => {
+                error!(
+                    "Background re-hash failed for {} path={}: {}",
+                    key.root.display(),
+                    key.path.display(),
+                    e
+                );
+            }
+        }
+    });
 }
 
 /// Restore watchers from persisted state
@@ -478,122 +1604,286 @@ async fn restore_watchers(state: &Arc<AppState>) {
         }
 
         // Register subscription
-        let key = protocol::make_subscription_key(
-            &entry.root.to_string_lossy(),
-            &entry.path,
-            &entry.glob,
-        );
+        ensure_ignore_rules(state, &entry.root).await;
         {
             let mut subs = state.subscriptions.write().await;
-            subs.insert(
-                key,
-                (entry.root.clone(), entry.path.clone(), entry.glob.clone()),
+            let mut sub = Subscription::new(
+                entry.root.clone(),
+                entry.path.clone(),
+                entry.glob.clone(),
+                entry.options.clone(),
+                entry.origin,
             );
+            sub.created_at = entry.created_at;
+            sub.last_access = entry.last_access;
+            subs.insert(entry.key(), sub);
         }
 
         // Background re-hash
-        let state_clone = state.clone();
-        tokio::spawn(async move {
-            debug!(
-                "Background re-hash for: {} path={} glob={}",
-                entry.root.display(),
-                entry.path,
-                entry.glob
-            );
-            let start = std::time::Instant::now();
-            let mut daemon = state_clone.daemon.write().await;
-            match daemon::hash(
-                &mut daemon,
-                &entry.root,
-                &entry.path,
-                &entry.glob,
-                false,
-                None,
-            ) {
-                Ok(result) => {
-                    info!(
-                        "Re-hash complete: {} path={} files={} duration={:?}",
-                        entry.root.display(),
-                        entry.path,
-                        result.file_count,
-                        start.elapsed()
-                    );
-                }
-                Err(e) => {
-                    error!(
-                        "Background re-hash failed for {} path={}: {}",
-                        entry.root.display(),
-                        entry.path,
-                        e
-                    );
-                }
-            }
-        });
+        spawn_rehash(
+            state,
+            GlobKey {
+                root: entry.root,
+                path: entry.path,
+                glob: entry.glob,
+            },
+        );
     }
 }
 
-/// Check if a changed file path matches a watch subscription
-fn matches_watch(
-    changed: &std::path::Path,
-    root: &std::path::Path,
-    path: &str,
-    glob_pattern: &str,
-) -> bool {
-    let watch_dir = match root.join(path).canonicalize() {
-        Ok(p) => p,
-        Err(_) => root.join(path),
-    };
-    let changed = match changed.canonicalize() {
-        Ok(p) => p,
-        Err(_) => changed.to_path_buf(),
-    };
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    if !changed.starts_with(&watch_dir) {
-        return false;
+    fn test_options() -> ServerOptions {
+        ServerOptions {
+            event_buffer: DEFAULT_EVENT_BUFFER,
+            watcher_buffer: DEFAULT_WATCHER_BUFFER,
+            reply_buffer: DEFAULT_REPLY_BUFFER,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            max_request_len: framing::DEFAULT_MAX_REQUEST_LEN,
+            request_timeout_ms: DEFAULT_REQUEST_TIMEOUT_MS,
+            max_subscriptions: DEFAULT_MAX_SUBSCRIPTIONS,
+            max_concurrent_requests: DEFAULT_MAX_CONCURRENT_REQUESTS,
+        }
     }
 
-    let rel_path = match changed.strip_prefix(&watch_dir) {
-        Ok(p) => p,
-        Err(_) => return false,
-    };
+    fn test_state(options: ServerOptions) -> (Arc<AppState>, mpsc::Receiver<notify::Event>) {
+        let (event_tx, event_rx) = mpsc::channel(100);
+        let state = AppState::new(options, PersistedState::default(), event_tx);
+        (state, event_rx)
+    }
 
-    let glob = match globset::Glob::new(glob_pattern) {
-        Ok(g) => g.compile_matcher(),
-        Err(_) => return false,
-    };
+    #[test]
+    fn test_walks_include_skips_hidden_and_ignored_paths() {
+        let dir = std::env::temp_dir().join("fswatchd-test-walks-include");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join(".git")).unwrap();
+        std::fs::write(dir.join(".gitignore"), "target/\n").unwrap();
+        let root = std::fs::canonicalize(&dir).unwrap();
+
+        let mut ignores = HashMap::new();
+        assert!(walks_include(&ignores, &root, &root.join("target/out.o")));
+        ignores.insert(root.clone(), IgnoreMatcher::build(&root));
+
+        assert!(walks_include(&ignores, &root, &root.join("src/lib.rs")));
+        assert!(!walks_include(
+            &ignores,
+            &root,
+            &root.join(".git/index.lock")
+        ));
+        assert!(!walks_include(&ignores, &root, &root.join("target/out.o")));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
 
-    glob.is_match(rel_path)
-}
+    #[tokio::test]
+    async fn test_disconnect_drops_caches_of_released_root() {
+        let dir = std::env::temp_dir().join("fswatchd-test-release-caches");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a.rs"), "fn a() {}").unwrap();
+        let root = std::fs::canonicalize(&dir).unwrap();
+
+        let (state, _event_rx) = test_state(test_options());
+        let backend = AppStateBackend {
+            state: state.clone(),
+            session: 1,
+        };
+        let options = WatchOptions::default();
+        backend
+            .watch(&root, Path::new("."), "**/*.rs", &options, false)
+            .await
+            .unwrap();
+        assert!(state.ignores.read().await.contains_key(&root));
+        let (before, _) = backend
+            .hash(&root, Path::new("."), "**/*.rs", false)
+            .await
+            .unwrap();
+
+        let keys: Vec<SubscriptionKey> = state
+            .subscriptions
+            .read()
+            .await
+            .iter()
+            .map(|(key, _)| key.clone())
+            .collect();
+        release_subscriptions(&state, 1, keys.iter()).await;
+        assert!(state.daemon.read().await.root_watchers.is_empty());
+        assert!(state.ignores.read().await.is_empty());
+
+        // Nothing reports this edit, so the cache must already be gone
+        std::fs::write(dir.join("a.rs"), "fn b() {}").unwrap();
+        let (after, _) = backend
+            .hash(&root, Path::new("."), "**/*.rs", false)
+            .await
+            .unwrap();
+        assert_ne!(before, after);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[tokio::test]
+    async fn test_disconnect_keeps_watcher_of_hashed_file_lists() {
+        let dir = std::env::temp_dir().join("fswatchd-test-release-file-lists");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a.rs"), "fn a() {}").unwrap();
+        let root = std::fs::canonicalize(&dir).unwrap();
+
+        let (state, _event_rx) = test_state(test_options());
+        let backend = AppStateBackend {
+            state: state.clone(),
+            session: 1,
+        };
+        backend
+            .hash_files(&root, vec![PathBuf::from("a.rs")])
+            .await
+            .unwrap();
+        backend
+            .watch(
+                &root,
+                Path::new("."),
+                "**/*.rs",
+                &WatchOptions::default(),
+                false,
+            )
+            .await
+            .unwrap();
+
+        let keys: Vec<SubscriptionKey> = state
+            .subscriptions
+            .read()
+            .await
+            .iter()
+            .map(|(key, _)| key.clone())
+            .collect();
+        release_subscriptions(&state, 1, keys.iter()).await;
+        let daemon = state.daemon.read().await;
+        assert!(daemon.root_watchers.contains_key(&root));
+        assert!(daemon.file_cache.contains_key(&root.join("a.rs")));
+        drop(daemon);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
 
-    #[test]
-    fn test_matches_watch_basic() {
-        let temp_dir = std::env::temp_dir().join("fswatchd-test-matches");
-        let _ = std::fs::create_dir_all(&temp_dir);
-        let test_file = temp_dir.join("test.rs");
-        let _ = std::fs::write(&test_file, "");
+    /// Watch a fresh directory from another session. The event loop is not running,
+    /// so syncs wait for cookies that never arrive.
+    async fn watched_dir(state: &Arc<AppState>, name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(name);
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let root = std::fs::canonicalize(&dir).unwrap();
+        let backend = AppStateBackend {
+            state: state.clone(),
+            session: 0,
+        };
+        backend
+            .watch(
+                &root,
+                Path::new("."),
+                "*.rs",
+                &WatchOptions::default(),
+                false,
+            )
+            .await
+            .unwrap();
+        root
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_sync_removes_its_cookie() {
+        let (state, _event_rx) = test_state(test_options());
+        let root = watched_dir(&state, "fswatchd-test-sync-cookie").await;
+
+        let sync = sync_root(&state, &root);
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), sync)
+                .await
+                .is_err()
+        );
 
-        assert!(matches_watch(&test_file, &temp_dir, ".", "*.rs"));
-        assert!(!matches_watch(&test_file, &temp_dir, ".", "*.txt"));
+        assert!(state.lock_cookies().is_empty());
+        assert_eq!(std::fs::read_dir(&root).unwrap().count(), 0);
 
-        let _ = std::fs::remove_dir_all(&temp_dir);
+        let _ = std::fs::remove_dir_all(&root);
     }
 
-    #[test]
-    fn test_matches_watch_nested() {
-        let temp_dir = std::env::temp_dir().join("fswatchd-test-nested");
-        let sub_dir = temp_dir.join("src");
-        let _ = std::fs::create_dir_all(&sub_dir);
-        let test_file = sub_dir.join("lib.rs");
-        let _ = std::fs::write(&test_file, "");
-
-        assert!(matches_watch(&test_file, &temp_dir, ".", "**/*.rs"));
-        assert!(matches_watch(&test_file, &temp_dir, "src", "*.rs"));
-        assert!(!matches_watch(&test_file, &temp_dir, "lib", "*.rs"));
-
-        let _ = std::fs::remove_dir_all(&temp_dir);
+    #[tokio::test]
+    async fn test_requests_beyond_the_concurrency_limit_fail() {
+        use tokio::io::{AsyncBufReadExt, BufReader};
+
+        let options = ServerOptions {
+            max_concurrent_requests: 1,
+            ..test_options()
+        };
+        let (state, _event_rx) = test_state(options);
+        let root = watched_dir(&state, "fswatchd-test-request-slots").await;
+
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(handle_connection(state.clone(), server));
+        let (reader, mut writer) = tokio::io::split(client);
+
+        // The first request waits for its sync, holding the only slot
+        let root = root.display().to_string();
+        let first = serde_json::json!({
+            "id": 1, "cmd": "hash", "root": root, "path": ".", "glob": "*.rs", "sync": true,
+        });
+        let second = serde_json::json!({"id": 2, "cmd": "status"});
+        let requests = format!("{}\n{}\n", first, second);
+        writer.write_all(requests.as_bytes()).await.unwrap();
+
+        let mut line = String::new();
+        BufReader::new(reader).read_line(&mut line).await.unwrap();
+        let reply: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(reply["id"], 2);
+        let error = reply["error"].as_str().unwrap();
+        assert!(error.contains("Too many concurrent requests"), "{}", error);
+
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_since_applies_ignore_rules_through_symlinked_roots() {
+        let dir = std::env::temp_dir().join("fswatchd-test-since-symlink");
+        let _ = std::fs::remove_dir_all(&dir);
+        let real = dir.join("real");
+        std::fs::create_dir_all(real.join(".git")).unwrap();
+        std::fs::create_dir_all(real.join("target")).unwrap();
+        std::fs::write(real.join(".gitignore"), "target/\n").unwrap();
+        std::fs::write(real.join("a.rs"), "").unwrap();
+        std::fs::write(real.join("target/out.rs"), "").unwrap();
+        let root = dir.join("link");
+        std::os::unix::fs::symlink(&real, &root).unwrap();
+
+        let (state, _event_rx) = test_state(test_options());
+        let backend = AppStateBackend {
+            state: state.clone(),
+            session: 1,
+        };
+        backend
+            .watch(
+                &root,
+                Path::new("."),
+                "**/*.rs",
+                &WatchOptions::default(),
+                false,
+            )
+            .await
+            .unwrap();
+        let clock = backend.clock(&root).await.unwrap();
+
+        let (a, out) = (root.join("a.rs"), root.join("target/out.rs"));
+        daemon::advance_clock(&mut *state.daemon.write().await, &[&a, &out]);
+
+        let (_, files) = backend
+            .since(&root, Path::new("."), "**/*.rs", &clock)
+            .await
+            .unwrap();
+        let paths: Vec<PathBuf> = files.unwrap().into_iter().map(|f| f.path.0).collect();
+        assert_eq!(paths, vec![a]);
+
+        let _ = std::fs::remove_dir_all(&dir);
     }
 }
@@ -3,15 +3,49 @@
 //! This module contains the per-connection session logic, separated from
 //! the actual I/O to enable unit testing.
 
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::future::Future;
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
 
-use crate::protocol::{self, Request, Response, SubscriptionKey};
+use crate::persistence;
+use crate::protocol::{
+    self, ChangedFile, DaemonStatus, FileError, Framing, HashOutcome, HashSpec, InvalidateScope,
+    ListEntry, Request, Response, SubscriptionKey, UnwatchTarget, WatchOptions,
+};
 
 /// Boxed future for hash operations
 type HashFuture<'a> = Pin<Box<dyn Future<Output = Result<(String, usize), String>> + Send + 'a>>;
 
+/// Boxed future for batch hash operations, one result per spec
+type HashManyFuture<'a> =
+    Pin<Box<dyn Future<Output = Vec<Result<(String, usize), String>>> + Send + 'a>>;
+
+/// Boxed future for explicit file list hashes: hash, file count and unreadable files
+type HashFilesFuture<'a> =
+    Pin<Box<dyn Future<Output = Result<(String, usize, Vec<FileError>), String>> + Send + 'a>>;
+
+/// Boxed future for status reports
+type StatusFuture<'a> = Pin<Box<dyn Future<Output = DaemonStatus> + Send + 'a>>;
+
+/// Boxed future for listings: (subscriptions, persisted entries)
+type ListFuture<'a> = Pin<Box<dyn Future<Output = (Vec<ListEntry>, Vec<ListEntry>)> + Send + 'a>>;
+
+/// Boxed future for invalidation: (file entries, results) dropped
+type InvalidateFuture<'a> =
+    Pin<Box<dyn Future<Output = Result<(usize, usize), String>> + Send + 'a>>;
+
+/// Boxed future for sync barriers
+type SyncFuture<'a> = Pin<Box<dyn Future<Output = Result<(), String>> + Send + 'a>>;
+
+/// Boxed future for clock reads
+type ClockFuture<'a> = Pin<Box<dyn Future<Output = Option<String>> + Send + 'a>>;
+
+/// Boxed future for `since` queries: current clock, and the changed files unless the
+/// client has to start fresh
+type SinceFuture<'a> =
+    Pin<Box<dyn Future<Output = Result<(String, Option<Vec<ChangedFile>>), String>> + Send + 'a>>;
+
 /// Boxed future for watch operations
 type WatchFuture<'a> = Pin<Box<dyn Future<Output = Result<(), String>> + Send + 'a>>;
 
@@ -20,45 +54,250 @@ type WatchFuture<'a> = Pin<Box<dyn Future<Output = Result<(), String>> + Send +
 pub enum RequestResult {
     /// Send response to client
     Response(Response),
-    /// Send response and add subscription
+    /// Send response; the session now holds a new subscription
     Subscribe {
         response: Response,
         key: SubscriptionKey,
     },
     /// Send response and remove subscription
     Unsubscribe { response: Response },
+    /// Send response and shut the daemon down
+    Shutdown { response: Response },
 }
 
-/// Boxed future for unwatch operations
-type UnwatchFuture<'a> = Pin<Box<dyn Future<Output = Result<(), String>> + Send + 'a>>;
+/// Boxed future for unwatch operations, yielding the removed subscription keys
+type UnwatchFuture<'a> =
+    Pin<Box<dyn Future<Output = Result<Vec<SubscriptionKey>, String>> + Send + 'a>>;
 
 /// Trait for the backend that handles actual hash/watch operations.
 /// This allows mocking in tests. Uses async methods for real implementation.
 pub trait SessionBackend: Send + Sync {
-    fn hash(&self, root: &str, path: &str, glob: &str, persistent: bool) -> HashFuture<'_>;
+    fn hash(&self, root: &Path, path: &Path, glob: &str, persistent: bool) -> HashFuture<'_>;
+
+    fn hash_many(&self, specs: Vec<HashSpec>) -> HashManyFuture<'_>;
+
+    fn hash_files(&self, root: &Path, files: Vec<PathBuf>) -> HashFilesFuture<'_>;
+
+    /// Start watching and register the subscription, held by this session
+    fn watch(
+        &self,
+        root: &Path,
+        path: &Path,
+        glob: &str,
+        options: &WatchOptions,
+        durable: bool,
+    ) -> WatchFuture<'_>;
+
+    fn unwatch(&self, target: UnwatchTarget) -> UnwatchFuture<'_>;
+
+    fn status(&self) -> StatusFuture<'_>;
+
+    fn list(&self) -> ListFuture<'_>;
 
-    fn watch(&self, root: &str, path: &str, glob: &str) -> WatchFuture<'_>;
+    fn invalidate(&self, scope: InvalidateScope, rehash: bool) -> InvalidateFuture<'_>;
 
-    fn unwatch(&self, key: &str) -> UnwatchFuture<'_>;
+    /// Wait until all changes made under `root` before the call have been applied
+    fn sync(&self, root: &Path) -> SyncFuture<'_>;
+
+    /// Current clock of a watched root
+    fn clock(&self, root: &Path) -> ClockFuture<'_>;
+
+    fn since(&self, root: &Path, path: &Path, glob: &str, clock: &str) -> SinceFuture<'_>;
 }
 
 /// Per-connection session state
 pub struct Session {
     subscriptions: HashSet<SubscriptionKey>,
+    /// Protocol version negotiated by `hello`; the oldest supported until then
+    protocol_version: u32,
+    /// Framing chosen by `hello`, used for everything after its response
+    framing: Framing,
+    /// Most subscriptions this session may hold
+    max_subscriptions: usize,
 }
 
 impl Session {
     pub fn new() -> Self {
         Self {
             subscriptions: HashSet::new(),
+            protocol_version: protocol::MIN_PROTOCOL_VERSION,
+            framing: Framing::Ndjson,
+            max_subscriptions: usize::MAX,
         }
     }
 
+    /// Limit the number of subscriptions this session may hold
+    pub fn set_max_subscriptions(&mut self, max: usize) {
+        self.max_subscriptions = max;
+    }
+
+    /// Protocol version in use on this connection
+    pub fn protocol_version(&self) -> u32 {
+        self.protocol_version
+    }
+
+    /// Framing in use on this connection
+    pub fn framing(&self) -> Framing {
+        self.framing
+    }
+
+    /// Subscriptions held by this session, released when it ends
+    pub fn subscriptions(&self) -> impl Iterator<Item = &SubscriptionKey> {
+        self.subscriptions.iter()
+    }
+
     /// Check if this session should receive an event for the given key
     pub fn should_receive_event(&self, key: &SubscriptionKey) -> bool {
         self.subscriptions.contains(key)
     }
 
+    /// Process a request that does not touch session state.
+    ///
+    /// Takes no session, so the connection can run it concurrently with other requests.
+    pub async fn process_stateless<B: SessionBackend>(request: Request, backend: &B) -> Response {
+        match request {
+            Request::Hash {
+                root,
+                path,
+                glob,
+                persistent,
+                sync,
+            } => {
+                if persistent && let Err(e) = persistence::check_persistable(&root, &path) {
+                    return Response::Error { error: e };
+                }
+                if sync && let Err(e) = backend.sync(&root).await {
+                    return Response::Error { error: e };
+                }
+                // Read the clock first so changes made while hashing show up in `since`
+                let clock = backend.clock(&root).await;
+                match backend.hash(&root, &path, &glob, persistent).await {
+                    Ok((hash, file_count)) => Response::Hash {
+                        hash,
+                        file_count,
+                        clock: match clock {
+                            Some(clock) => Some(clock),
+                            // A persistent hash may have just started the watcher
+                            None => backend.clock(&root).await,
+                        },
+                    },
+                    Err(e) => Response::Error { error: e },
+                }
+            }
+            Request::HashMany { specs, sync } => {
+                if sync {
+                    let roots: HashSet<&Path> = specs.iter().map(|spec| &*spec.root).collect();
+                    for root in roots {
+                        if let Err(e) = backend.sync(root).await {
+                            return Response::Error { error: e };
+                        }
+                    }
+                }
+                let roots: Vec<PathBuf> = specs.iter().map(|spec| spec.root.0.clone()).collect();
+                let mut clocks: HashMap<PathBuf, Option<String>> = HashMap::new();
+                for root in &roots {
+                    if !clocks.contains_key(root) {
+                        clocks.insert(root.clone(), backend.clock(root).await);
+                    }
+                }
+
+                // Persistent specs that cannot be stored fail on their own
+                let rejected: Vec<Option<String>> = specs
+                    .iter()
+                    .map(|spec| {
+                        let check = || persistence::check_persistable(&spec.root, &spec.path);
+                        spec.persistent.then(check).and_then(Result::err)
+                    })
+                    .collect();
+                let accepted = specs
+                    .into_iter()
+                    .zip(&rejected)
+                    .filter(|(_, rejected)| rejected.is_none())
+                    .map(|(spec, _)| spec)
+                    .collect();
+                let mut hashed = backend.hash_many(accepted).await.into_iter();
+
+                let mut results = Vec::new();
+                for (root, rejected) in roots.iter().zip(rejected) {
+                    let result = match rejected {
+                        Some(error) => Err(error),
+                        None => hashed.next().expect("one result per spec"),
+                    };
+                    results.push(match result {
+                        Ok((hash, file_count)) => HashOutcome::Hash {
+                            hash,
+                            file_count,
+                            clock: match clocks[root].clone() {
+                                Some(clock) => Some(clock),
+                                None => backend.clock(root).await,
+                            },
+                        },
+                        Err(error) => HashOutcome::Error { error },
+                    });
+                }
+                Response::HashMany { results }
+            }
+            Request::HashFiles { root, files, sync } => {
+                if sync && let Err(e) = backend.sync(&root).await {
+                    return Response::Error { error: e };
+                }
+                let clock = backend.clock(&root).await;
+                let files = files.into_iter().map(|file| file.0).collect();
+                match backend.hash_files(&root, files).await {
+                    Ok((hash, file_count, missing)) => Response::HashFiles {
+                        hash,
+                        file_count,
+                        missing,
+                        clock: match clock {
+                            Some(clock) => Some(clock),
+                            // Hashing a file list starts the watcher
+                            None => backend.clock(&root).await,
+                        },
+                    },
+                    Err(e) => Response::Error { error: e },
+                }
+            }
+            Request::Since {
+                root,
+                path,
+                glob,
+                clock,
+                sync,
+            } => {
+                if sync && let Err(e) = backend.sync(&root).await {
+                    return Response::Error { error: e };
+                }
+                match backend.since(&root, &path, &glob, &clock).await {
+                    Ok((clock, files)) => Response::Since {
+                        clock,
+                        fresh_instance: files.is_none(),
+                        files: files.unwrap_or_default(),
+                    },
+                    Err(e) => Response::Error { error: e },
+                }
+            }
+            Request::Status => Response::Status(backend.status().await),
+            Request::List => {
+                let (subscriptions, persisted) = backend.list().await;
+                Response::List {
+                    subscriptions,
+                    persisted,
+                }
+            }
+            Request::Invalidate { scope, rehash } => {
+                match backend.invalidate(scope, rehash).await {
+                    Ok((files, results)) => Response::Invalidated { files, results },
+                    Err(e) => Response::Error {
+                        error: format!("Failed to invalidate: {}", e),
+                    },
+                }
+            }
+            _ => Response::Error {
+                error: "Request requires a session".to_string(),
+            },
+        }
+    }
+
     /// Process a request and return the result
     pub async fn process_request<B: SessionBackend>(
         &mut self,
@@ -66,28 +305,71 @@ impl Session {
         backend: &B,
     ) -> RequestResult {
         match request {
-            Request::Hash {
+            Request::Hello { protocol, framing } => match protocol::negotiate_version(protocol) {
+                Some(version) => {
+                    self.protocol_version = version;
+                    self.framing = framing;
+                    RequestResult::Response(Response::Hello {
+                        protocol: version,
+                        version: env!("CARGO_PKG_VERSION").to_string(),
+                        capabilities: protocol::Capabilities::current(),
+                        framing,
+                    })
+                }
+                None => RequestResult::Response(Response::Error {
+                    error: format!(
+                        "Unsupported protocol version {} (supported: {}-{})",
+                        protocol,
+                        protocol::MIN_PROTOCOL_VERSION,
+                        protocol::PROTOCOL_VERSION
+                    ),
+                }),
+            },
+
+            request @ (Request::Hash { .. }
+            | Request::HashMany { .. }
+            | Request::HashFiles { .. }
+            | Request::Status
+            | Request::List
+            | Request::Invalidate { .. }
+            | Request::Since { .. }) => {
+                RequestResult::Response(Self::process_stateless(request, backend).await)
+            }
+
+            Request::Watch {
                 root,
                 path,
                 glob,
-                persistent,
-            } => match backend.hash(&root, &path, &glob, persistent).await {
-                Ok((hash, file_count)) => {
-                    RequestResult::Response(Response::Hash { hash, file_count })
+                options,
+                durable,
+            } => {
+                let key = protocol::make_watch_key(&root, &path, &glob, &options);
+
+                if !self.subscriptions.contains(&key)
+                    && self.subscriptions.len() >= self.max_subscriptions
+                {
+                    return RequestResult::Response(Response::Error {
+                        error: format!(
+                            "Subscription limit of {} reached; unwatch something first",
+                            self.max_subscriptions
+                        ),
+                    });
                 }
-                Err(e) => RequestResult::Response(Response::Error { error: e }),
-            },
 
-            Request::Watch { root, path, glob } => {
-                let key = protocol::make_subscription_key(&root, &path, &glob);
+                if durable && let Err(e) = persistence::check_persistable(&root, &path) {
+                    return RequestResult::Response(Response::Error { error: e });
+                }
 
-                if let Err(e) = backend.watch(&root, &path, &glob).await {
+                if let Err(e) = backend.watch(&root, &path, &glob, &options, durable).await {
                     return RequestResult::Response(Response::Error {
                         error: format!("Failed to start watcher: {}", e),
                     });
                 }
 
-                self.subscriptions.insert(key.clone());
+                // The session holds each subscription once
+                if !self.subscriptions.insert(key.clone()) {
+                    return RequestResult::Response(Response::Watch { key });
+                }
 
                 RequestResult::Subscribe {
                     response: Response::Watch { key: key.clone() },
@@ -95,18 +377,31 @@ impl Session {
                 }
             }
 
-            Request::Unwatch { key } => {
-                self.subscriptions.remove(&key);
-                // Clean up backend subscription and potentially stop watcher
-                if let Err(e) = backend.unwatch(&key).await {
-                    return RequestResult::Response(Response::Error {
-                        error: format!("Failed to unwatch: {}", e),
-                    });
+            Request::Unwatch { target } => {
+                if let UnwatchTarget::Key { key } = &target {
+                    self.subscriptions.remove(key);
+                }
+                // Clean up backend subscriptions and persisted entries, potentially stopping watchers
+                match backend.unwatch(target).await {
+                    Ok(removed) => {
+                        for key in &removed {
+                            self.subscriptions.remove(key);
+                        }
+                    }
+                    Err(e) => {
+                        return RequestResult::Response(Response::Error {
+                            error: format!("Failed to unwatch: {}", e),
+                        });
+                    }
                 }
                 RequestResult::Unsubscribe {
                     response: Response::Ok { ok: true },
                 }
             }
+
+            Request::Shutdown => RequestResult::Shutdown {
+                response: Response::Ok { ok: true },
+            },
         }
     }
 }
@@ -124,17 +419,128 @@ mod tests {
     struct MockBackend;
 
     impl SessionBackend for MockBackend {
-        fn hash(&self, _root: &str, _path: &str, _glob: &str, _persistent: bool) -> HashFuture<'_> {
+        fn hash(
+            &self,
+            _root: &Path,
+            _path: &Path,
+            _glob: &str,
+            _persistent: bool,
+        ) -> HashFuture<'_> {
             Box::pin(async { Ok(("abc123".to_string(), 5)) })
         }
 
-        fn watch(&self, _root: &str, _path: &str, _glob: &str) -> WatchFuture<'_> {
-            Box::pin(async { Ok(()) })
+        fn hash_many(&self, specs: Vec<HashSpec>) -> HashManyFuture<'_> {
+            Box::pin(async move {
+                specs
+                    .into_iter()
+                    .map(|spec| match spec.glob.as_str() {
+                        "[" => Err("bad glob".to_string()),
+                        _ => Ok(("abc123".to_string(), 5)),
+                    })
+                    .collect()
+            })
         }
 
-        fn unwatch(&self, _key: &str) -> UnwatchFuture<'_> {
+        fn watch(
+            &self,
+            _root: &Path,
+            _path: &Path,
+            _glob: &str,
+            _options: &WatchOptions,
+            _durable: bool,
+        ) -> WatchFuture<'_> {
             Box::pin(async { Ok(()) })
         }
+
+        fn unwatch(&self, target: UnwatchTarget) -> UnwatchFuture<'_> {
+            Box::pin(async move {
+                match target {
+                    UnwatchTarget::Key { key } => Ok(vec![key]),
+                    UnwatchTarget::Spec { root, path, glob } => Ok(vec![protocol::make_watch_key(
+                        &root,
+                        &path,
+                        &glob,
+                        &WatchOptions::default(),
+                    )]),
+                }
+            })
+        }
+
+        fn list(&self) -> ListFuture<'_> {
+            Box::pin(async { (Vec::new(), Vec::new()) })
+        }
+
+        fn invalidate(&self, scope: InvalidateScope, _rehash: bool) -> InvalidateFuture<'_> {
+            Box::pin(async move {
+                match scope {
+                    InvalidateScope::Key { .. } => Err("Unknown subscription key".to_string()),
+                    InvalidateScope::Root { .. } => Ok((3, 1)),
+                }
+            })
+        }
+
+        fn hash_files(&self, _root: &Path, files: Vec<PathBuf>) -> HashFilesFuture<'_> {
+            Box::pin(async move {
+                let (present, missing): (Vec<PathBuf>, Vec<PathBuf>) = files
+                    .into_iter()
+                    .partition(|file| !file.to_string_lossy().starts_with("missing"));
+                let missing = missing
+                    .into_iter()
+                    .map(|path| FileError {
+                        path: path.into(),
+                        error: "No such file or directory".to_string(),
+                    })
+                    .collect();
+                Ok(("abc123".to_string(), present.len(), missing))
+            })
+        }
+
+        fn sync(&self, root: &Path) -> SyncFuture<'_> {
+            let result = match root.to_str() {
+                Some("/offline") => Err("Timed out waiting for the watcher".to_string()),
+                _ => Ok(()),
+            };
+            Box::pin(async move { result })
+        }
+
+        fn clock(&self, root: &Path) -> ClockFuture<'_> {
+            let clock = (root == Path::new("/repo")).then(|| "c:test:1".to_string());
+            Box::pin(async move { clock })
+        }
+
+        fn since(&self, _root: &Path, _path: &Path, _glob: &str, clock: &str) -> SinceFuture<'_> {
+            let files = (clock == "c:test:1").then(|| {
+                vec![ChangedFile {
+                    path: PathBuf::from("/repo/src/a.rs").into(),
+                    exists: true,
+                }]
+            });
+            Box::pin(async move { Ok(("c:test:2".to_string(), files)) })
+        }
+
+        fn status(&self) -> StatusFuture<'_> {
+            let cache = protocol::CacheStatus {
+                entries: 0,
+                hits: 0,
+                misses: 0,
+            };
+            Box::pin(async move {
+                DaemonStatus {
+                    version: "test".to_string(),
+                    uptime_secs: 0,
+                    roots: Vec::new(),
+                    subscriptions: 0,
+                    persisted_entries: 0,
+                    connected_clients: 1,
+                    file_cache: cache,
+                    result_cache: cache,
+                    pending_invalidations: 0,
+                    pending_notifications: 0,
+                    dropped_events: 0,
+                    lagged_events: 0,
+                }
+            })
+        }
     }
 
     #[tokio::test]
@@ -143,12 +549,14 @@ mod tests {
         let backend = MockBackend;
 
         let request = Request::Watch {
-            root: "/repo".to_string(),
-            path: "src".to_string(),
+            root: "/repo".into(),
+            path: "src".into(),
             glob: "**/*.rs".to_string(),
+            options: WatchOptions::default(),
+            durable: false,
         };
 
-        let result = session.process_request(request, &backend).await;
+        let result = session.process_request(request.clone(), &backend).await;
 
         match result {
             RequestResult::Subscribe { key, .. } => {
@@ -156,6 +564,46 @@ mod tests {
             }
             _ => panic!("Expected Subscribe"),
         }
+
+        // Watching the same spec again does not take a second hold
+        let result = session.process_request(request, &backend).await;
+        assert!(matches!(
+            result,
+            RequestResult::Response(Response::Watch { .. })
+        ));
+        assert_eq!(session.subscriptions().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_watch_respects_subscription_limit() {
+        let mut session = Session::new();
+        session.set_max_subscriptions(1);
+        let backend = MockBackend;
+
+        let watch = |glob: &str| Request::Watch {
+            root: "/repo".into(),
+            path: "src".into(),
+            glob: glob.to_string(),
+            options: WatchOptions::default(),
+            durable: false,
+        };
+
+        let result = session.process_request(watch("*.rs"), &backend).await;
+        assert!(matches!(result, RequestResult::Subscribe { .. }));
+
+        // Re-watching a held spec is still allowed
+        let result = session.process_request(watch("*.rs"), &backend).await;
+        assert!(matches!(
+            result,
+            RequestResult::Response(Response::Watch { .. })
+        ));
+
+        match session.process_request(watch("*.ts"), &backend).await {
+            RequestResult::Response(Response::Error { error }) => {
+                assert!(error.contains("limit of 1"));
+            }
+            other => panic!("Expected error, got {:?}", other),
+        }
     }
 
     #[tokio::test]
@@ -165,9 +613,11 @@ mod tests {
 
         // First subscribe
         let request: Request = Request::Watch {
-            root: "/repo".to_string(),
-            path: "src".to_string(),
+            root: "/repo".into(),
+            path: "src".into(),
             glob: "**/*.rs".to_string(),
+            options: WatchOptions::default(),
+            durable: false,
         };
         let key = match session.process_request(request, &backend).await {
             RequestResult::Subscribe { key, .. } => key,
@@ -177,21 +627,325 @@ mod tests {
         assert!(session.should_receive_event(&key));
 
         // Now unsubscribe
-        let request = Request::Unwatch { key: key.clone() };
+        let request = Request::Unwatch {
+            target: UnwatchTarget::Key { key: key.clone() },
+        };
         session.process_request(request, &backend).await;
 
         assert!(!session.should_receive_event(&key));
     }
 
+    #[tokio::test]
+    async fn test_unwatch_by_spec_removes_subscription() {
+        let mut session = Session::new();
+        let backend = MockBackend;
+
+        let request = Request::Watch {
+            root: "/repo".into(),
+            path: "src".into(),
+            glob: "*.rs".to_string(),
+            options: WatchOptions::default(),
+            durable: false,
+        };
+        let key = match session.process_request(request, &backend).await {
+            RequestResult::Subscribe { key, .. } => key,
+            _ => panic!("Expected Subscribe"),
+        };
+
+        let request = Request::Unwatch {
+            target: UnwatchTarget::Spec {
+                root: "/repo".into(),
+                path: "src".into(),
+                glob: "*.rs".to_string(),
+            },
+        };
+        session.process_request(request, &backend).await;
+
+        assert!(!session.should_receive_event(&key));
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_request() {
+        let mut session = Session::new();
+        match session
+            .process_request(Request::Shutdown, &MockBackend)
+            .await
+        {
+            RequestResult::Shutdown {
+                response: Response::Ok { ok: true },
+            } => {}
+            _ => panic!("Expected Shutdown"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_hash_many_reports_per_spec_errors() {
+        let mut session = Session::new();
+        let backend = MockBackend;
+
+        let spec = |glob: &str| HashSpec {
+            root: "/repo".into(),
+            path: "src".into(),
+            glob: glob.to_string(),
+            persistent: false,
+        };
+        let request = Request::HashMany {
+            specs: vec![spec("*.rs"), spec("["), spec("*.ts")],
+            sync: false,
+        };
+
+        match session.process_request(request, &backend).await {
+            RequestResult::Response(Response::HashMany { results }) => {
+                assert_eq!(results.len(), 3);
+                assert!(matches!(results[0], HashOutcome::Hash { .. }));
+                assert!(matches!(results[1], HashOutcome::Error { .. }));
+                assert!(matches!(results[2], HashOutcome::Hash { .. }));
+            }
+            _ => panic!("Expected HashMany"),
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_persisting_rejects_non_utf8_paths() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let mut session = Session::new();
+        let path = PathBuf::from(OsStr::from_bytes(b"src/\xff"));
+
+        let watch = |durable| Request::Watch {
+            root: "/repo".into(),
+            path: path.clone().into(),
+            glob: "*.rs".to_string(),
+            options: WatchOptions::default(),
+            durable,
+        };
+        match session.process_request(watch(true), &MockBackend).await {
+            RequestResult::Response(Response::Error { error }) => {
+                assert!(error.contains("UTF-8"), "{}", error);
+            }
+            _ => panic!("Expected Error"),
+        }
+        assert!(matches!(
+            session.process_request(watch(false), &MockBackend).await,
+            RequestResult::Subscribe { .. }
+        ));
+
+        let spec = |persistent| HashSpec {
+            root: "/repo".into(),
+            path: path.clone().into(),
+            glob: "*.rs".to_string(),
+            persistent,
+        };
+        let request = Request::HashMany {
+            specs: vec![spec(true), spec(false)],
+            sync: false,
+        };
+        match session.process_request(request, &MockBackend).await {
+            RequestResult::Response(Response::HashMany { results }) => {
+                assert!(matches!(results[0], HashOutcome::Error { .. }));
+                assert!(matches!(results[1], HashOutcome::Hash { .. }));
+            }
+            _ => panic!("Expected HashMany"),
+        }
+
+        let request = Request::Hash {
+            root: "/repo".into(),
+            path: path.clone().into(),
+            glob: "*.rs".to_string(),
+            persistent: true,
+            sync: false,
+        };
+        assert!(matches!(
+            session.process_request(request, &MockBackend).await,
+            RequestResult::Response(Response::Error { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_status_reports_backend_status() {
+        let mut session = Session::new();
+        let backend = MockBackend;
+
+        match session.process_request(Request::Status, &backend).await {
+            RequestResult::Response(Response::Status(status)) => {
+                assert_eq!(status.connected_clients, 1);
+            }
+            _ => panic!("Expected Status"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_hash_sync_failure_is_reported() {
+        let mut session = Session::new();
+        let request = |root: &str| Request::Hash {
+            root: root.into(),
+            path: "src".into(),
+            glob: "*.rs".to_string(),
+            persistent: false,
+            sync: true,
+        };
+
+        match session
+            .process_request(request("/repo"), &MockBackend)
+            .await
+        {
+            RequestResult::Response(Response::Hash { clock, .. }) => {
+                assert_eq!(clock.as_deref(), Some("c:test:1"));
+            }
+            _ => panic!("Expected Hash"),
+        }
+        match session
+            .process_request(request("/offline"), &MockBackend)
+            .await
+        {
+            RequestResult::Response(Response::Error { .. }) => {}
+            _ => panic!("Expected Error"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_hash_files_reports_missing_per_entry() {
+        let mut session = Session::new();
+        let request = Request::HashFiles {
+            root: "/repo".into(),
+            files: vec!["a.c".into(), "missing.h".into()],
+            sync: false,
+        };
+
+        match session.process_request(request, &MockBackend).await {
+            RequestResult::Response(Response::HashFiles {
+                file_count,
+                missing,
+                ..
+            }) => {
+                assert_eq!(file_count, 1);
+                assert_eq!(missing.len(), 1);
+                assert_eq!(missing[0].path, "missing.h".into());
+            }
+            _ => panic!("Expected HashFiles"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_since_reports_fresh_instance() {
+        let mut session = Session::new();
+        let request = |clock: &str| Request::Since {
+            root: "/repo".into(),
+            path: "src".into(),
+            glob: "*.rs".to_string(),
+            clock: clock.to_string(),
+            sync: false,
+        };
+
+        match session
+            .process_request(request("c:test:1"), &MockBackend)
+            .await
+        {
+            RequestResult::Response(Response::Since {
+                clock,
+                fresh_instance,
+                files,
+            }) => {
+                assert_eq!(clock, "c:test:2");
+                assert!(!fresh_instance);
+                assert_eq!(files.len(), 1);
+            }
+            _ => panic!("Expected Since"),
+        }
+
+        match session
+            .process_request(request("c:old:1"), &MockBackend)
+            .await
+        {
+            RequestResult::Response(Response::Since {
+                fresh_instance,
+                files,
+                ..
+            }) => {
+                assert!(fresh_instance);
+                assert!(files.is_empty());
+            }
+            _ => panic!("Expected Since"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_invalidate_reports_counts_and_errors() {
+        let mut session = Session::new();
+        let backend = MockBackend;
+
+        let request = Request::Invalidate {
+            scope: InvalidateScope::Root {
+                root: "/repo".into(),
+                path: None,
+            },
+            rehash: false,
+        };
+        match session.process_request(request, &backend).await {
+            RequestResult::Response(Response::Invalidated { files, results }) => {
+                assert_eq!((files, results), (3, 1));
+            }
+            _ => panic!("Expected Invalidated"),
+        }
+
+        let request = Request::Invalidate {
+            scope: InvalidateScope::Key {
+                key: "missing".to_string(),
+            },
+            rehash: false,
+        };
+        match session.process_request(request, &backend).await {
+            RequestResult::Response(Response::Error { .. }) => {}
+            _ => panic!("Expected Error"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_hello_negotiates_version() {
+        let mut session = Session::new();
+        let backend = MockBackend;
+
+        let request = Request::Hello {
+            protocol: protocol::PROTOCOL_VERSION + 1,
+            framing: Framing::Msgpack,
+        };
+        match session.process_request(request, &backend).await {
+            RequestResult::Response(Response::Hello {
+                protocol,
+                capabilities,
+                ..
+            }) => {
+                assert_eq!(protocol, protocol::PROTOCOL_VERSION);
+                assert!(capabilities.commands.contains(&"hash".to_string()));
+            }
+            _ => panic!("Expected Hello"),
+        }
+        assert_eq!(session.protocol_version(), protocol::PROTOCOL_VERSION);
+        assert_eq!(session.framing(), Framing::Msgpack);
+
+        let request = Request::Hello {
+            protocol: 0,
+            framing: Framing::Ndjson,
+        };
+        match session.process_request(request, &backend).await {
+            RequestResult::Response(Response::Error { .. }) => {}
+            _ => panic!("Expected Error"),
+        }
+    }
+
     #[tokio::test]
     async fn test_should_receive_event_only_for_subscribed_keys() {
         let mut session = Session::new();
         let backend = MockBackend;
 
         let request = Request::Watch {
-            root: "/repo".to_string(),
-            path: "src".to_string(),
+            root: "/repo".into(),
+            path: "src".into(),
             glob: "**/*.rs".to_string(),
+            options: WatchOptions::default(),
+            durable: false,
         };
         let key = match session.process_request(request, &backend).await {
             RequestResult::Subscribe { key, .. } => key,
@@ -0,0 +1,337 @@
+//! Subscription registry with precompiled matchers.
+//!
+//! Globs are compiled and watch directories canonicalized once when a subscription
+//! is registered, so matching a burst of change events stays cheap.
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use tracing::warn;
+
+use crate::hasher::{self, IgnoreMatcher};
+use crate::persistence;
+use crate::protocol::{EntryOrigin, SubscriptionKey, WatchOptions};
+
+/// Identifies a client connection
+pub type SessionId = u64;
+
+/// An active subscription
+pub struct Subscription {
+    pub root: PathBuf,
+    pub path: PathBuf,
+    pub glob: String,
+    pub options: WatchOptions,
+    pub origin: EntryOrigin,
+    /// Unix seconds when the subscription was registered
+    pub created_at: u64,
+    /// Unix seconds when a client last requested the subscription
+    pub last_access: u64,
+    /// Connected sessions holding the subscription
+    pub holders: HashSet<SessionId>,
+    /// Canonicalized `root/path`
+    watch_dir: PathBuf,
+}
+
+impl Subscription {
+    pub fn new(
+        root: PathBuf,
+        path: PathBuf,
+        glob: String,
+        options: WatchOptions,
+        origin: EntryOrigin,
+    ) -> Self {
+        let watch_dir = canonicalize_lossy(&root.join(&path));
+        let now = persistence::unix_now();
+        Self {
+            root,
+            path,
+            glob,
+            options,
+            origin,
+            created_at: now,
+            last_access: now,
+            holders: HashSet::new(),
+            watch_dir,
+        }
+    }
+
+    /// Glob matching absolute paths: the escaped watch directory joined with the glob
+    fn absolute_glob(&self) -> Result<Glob, globset::Error> {
+        let dir = self.watch_dir.to_string_lossy();
+        // Glob candidates use forward slashes on every platform
+        #[cfg(windows)]
+        let dir = dir.replace('\\', "/");
+        let dir = globset::escape(&dir);
+        let dir = dir.trim_end_matches('/');
+        Glob::new(&format!("{}/{}", dir, self.glob))
+    }
+}
+
+/// Active subscriptions by key, with a combined glob set over all of them
+#[derive(Default)]
+pub struct Subscriptions {
+    by_key: HashMap<SubscriptionKey, Subscription>,
+    set: GlobSet,
+    /// Glob set index -> subscription key
+    set_keys: Vec<SubscriptionKey>,
+}
+
+impl Subscriptions {
+    /// Register a subscription. Re-registering an existing key only refreshes its access time.
+    pub fn insert(&mut self, key: SubscriptionKey, sub: Subscription) {
+        if let Some(existing) = self.by_key.get_mut(&key) {
+            existing.last_access = existing.last_access.max(sub.last_access);
+            return;
+        }
+        self.by_key.insert(key, sub);
+        self.rebuild();
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<Subscription> {
+        let removed = self.by_key.remove(key);
+        if removed.is_some() {
+            self.rebuild();
+        }
+        removed
+    }
+
+    /// Take a session's hold on a subscription
+    pub fn hold(&mut self, key: &str, session: SessionId) {
+        if let Some(sub) = self.by_key.get_mut(key) {
+            sub.holders.insert(session);
+        }
+    }
+
+    /// Release a session's hold. Returns true if the session held the subscription.
+    pub fn release(&mut self, key: &str, session: SessionId) -> bool {
+        self.by_key
+            .get_mut(key)
+            .is_some_and(|sub| sub.holders.remove(&session))
+    }
+
+    /// Whether no session holds the subscription
+    pub fn is_unheld(&self, key: &str) -> bool {
+        self.by_key
+            .get(key)
+            .is_some_and(|sub| sub.holders.is_empty())
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Subscription> {
+        self.by_key.get(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_key.len()
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.by_key.contains_key(key)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &Subscription> {
+        self.by_key.values()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&SubscriptionKey, &Subscription)> {
+        self.by_key.iter()
+    }
+
+    /// Keys of all subscriptions matching a changed path, honouring each root's ignore rules
+    pub fn matching(
+        &self,
+        changed: &Path,
+        ignores: &HashMap<PathBuf, IgnoreMatcher>,
+    ) -> Vec<&SubscriptionKey> {
+        let changed = canonicalize_lossy(changed);
+        let mut is_dir = None;
+
+        self.set
+            .matches(&changed)
+            .into_iter()
+            .map(|i| &self.set_keys[i])
+            .filter(|key| {
+                let sub = &self.by_key[*key];
+                let Ok(rel_path) = changed.strip_prefix(&sub.watch_dir) else {
+                    return false;
+                };
+
+                // Apply the same hidden/ignore rules as hashing
+                if hasher::is_hidden(rel_path) {
+                    return false;
+                }
+                match ignores.get(&sub.root) {
+                    Some(ignore) => {
+                        let is_dir = *is_dir.get_or_insert_with(|| changed.is_dir());
+                        !ignore.is_ignored(&changed, is_dir)
+                    }
+                    None => true,
+                }
+            })
+            .collect()
+    }
+
+    fn rebuild(&mut self) {
+        let mut builder = GlobSetBuilder::new();
+        self.set_keys.clear();
+
+        for (key, sub) in &self.by_key {
+            match sub.absolute_glob() {
+                Ok(glob) => {
+                    builder.add(glob);
+                    self.set_keys.push(key.clone());
+                }
+                Err(e) => warn!(glob = %sub.glob, "invalid subscription glob: {}", e),
+            }
+        }
+
+        self.set = builder.build().unwrap_or_else(|e| {
+            warn!("failed to build subscription glob set: {}", e);
+            self.set_keys.clear();
+            GlobSet::empty()
+        });
+    }
+}
+
+/// Canonicalize a path, falling back to its canonical parent for deleted files
+pub fn canonicalize_lossy(path: &Path) -> PathBuf {
+    if let Ok(p) = path.canonicalize() {
+        return p;
+    }
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => match parent.canonicalize() {
+            Ok(parent) => parent.join(name),
+            Err(_) => path.to_path_buf(),
+        },
+        _ => path.to_path_buf(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn subscriptions(root: &Path, specs: &[(&str, &str, &str)]) -> Subscriptions {
+        let mut subs = Subscriptions::default();
+        for (key, path, glob) in specs {
+            subs.insert(
+                key.to_string(),
+                Subscription::new(
+                    root.to_path_buf(),
+                    PathBuf::from(path),
+                    glob.to_string(),
+                    WatchOptions::default(),
+                    EntryOrigin::Watch,
+                ),
+            );
+        }
+        subs
+    }
+
+    fn matching(subs: &Subscriptions, changed: &Path) -> Vec<String> {
+        let mut keys: Vec<String> = subs
+            .matching(changed, &HashMap::new())
+            .into_iter()
+            .cloned()
+            .collect();
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn test_matches_watch_basic() {
+        let temp_dir = std::env::temp_dir().join("fswatchd-test-matches");
+        let _ = std::fs::create_dir_all(&temp_dir);
+        let test_file = temp_dir.join("test.rs");
+        let _ = std::fs::write(&test_file, "");
+
+        let subs = subscriptions(&temp_dir, &[("rs", ".", "*.rs"), ("txt", ".", "*.txt")]);
+        assert_eq!(matching(&subs, &test_file), vec!["rs"]);
+
+        let _ = std::fs::remove_dir_all(&temp_dir);
+    }
+
+    #[test]
+    fn test_matches_watch_nested() {
+        let temp_dir = std::env::temp_dir().join("fswatchd-test-nested");
+        let sub_dir = temp_dir.join("src");
+        let _ = std::fs::create_dir_all(&sub_dir);
+        let test_file = sub_dir.join("lib.rs");
+        let _ = std::fs::write(&test_file, "");
+
+        let subs = subscriptions(
+            &temp_dir,
+            &[
+                ("all", ".", "**/*.rs"),
+                ("src", "src", "*.rs"),
+                ("lib", "lib", "*.rs"),
+            ],
+        );
+        assert_eq!(matching(&subs, &test_file), vec!["all", "src"]);
+
+        let _ = std::fs::remove_dir_all(&temp_dir);
+    }
+
+    #[test]
+    fn test_matches_watch_ignored() {
+        let temp_dir = std::env::temp_dir().join("fswatchd-test-ignored");
+        let _ = std::fs::create_dir_all(temp_dir.join(".git"));
+        let _ = std::fs::create_dir_all(temp_dir.join("target/debug"));
+        let _ = std::fs::write(temp_dir.join(".gitignore"), "target/\n");
+        let ignored = temp_dir.join("target/debug/foo.rs");
+        let kept = temp_dir.join("main.rs");
+        let hidden = temp_dir.join(".cache/x.rs");
+        let _ = std::fs::write(&ignored, "");
+        let _ = std::fs::write(&kept, "");
+
+        let subs = subscriptions(&temp_dir, &[("rs", ".", "**/*.rs")]);
+        let mut ignores = HashMap::new();
+        ignores.insert(temp_dir.clone(), IgnoreMatcher::build(&temp_dir));
+
+        assert_eq!(subs.matching(&kept, &ignores).len(), 1);
+        assert!(subs.matching(&ignored, &ignores).is_empty());
+        assert_eq!(matching(&subs, &ignored), vec!["rs"]);
+        assert!(matching(&subs, &hidden).is_empty());
+
+        let _ = std::fs::remove_dir_all(&temp_dir);
+    }
+
+    #[test]
+    fn test_matches_deleted_file() {
+        let temp_dir = std::env::temp_dir().join("fswatchd-test-deleted");
+        let _ = std::fs::create_dir_all(&temp_dir);
+
+        let subs = subscriptions(&temp_dir, &[("rs", ".", "*.rs")]);
+        assert_eq!(matching(&subs, &temp_dir.join("gone.rs")), vec!["rs"]);
+
+        let _ = std::fs::remove_dir_all(&temp_dir);
+    }
+
+    #[test]
+    fn test_remove_subscription() {
+        let temp_dir = std::env::temp_dir().join("fswatchd-test-remove");
+        let _ = std::fs::create_dir_all(&temp_dir);
+
+        let mut subs = subscriptions(&temp_dir, &[("a", ".", "*.rs"), ("b", ".", "*.rs")]);
+        assert!(subs.remove("a").is_some());
+        assert_eq!(matching(&subs, &temp_dir.join("x.rs")), vec!["b"]);
+
+        let _ = std::fs::remove_dir_all(&temp_dir);
+    }
+
+    #[test]
+    fn test_release_after_last_holder() {
+        let temp_dir = std::env::temp_dir().join("fswatchd-test-holders");
+        let mut subs = subscriptions(&temp_dir, &[("a", ".", "*.rs")]);
+        subs.hold("a", 1);
+        subs.hold("a", 2);
+        subs.hold("a", 2);
+
+        assert!(subs.release("a", 1));
+        assert!(!subs.release("a", 1));
+        assert!(!subs.is_unheld("a"));
+        assert!(subs.release("a", 2));
+        assert!(subs.is_unheld("a"));
+        assert!(!subs.release("missing", 1));
+    }
+}
@@ -1,7 +1,171 @@
-//! Cross-platform IPC transport constants.
+//! Cross-platform IPC transport: where the daemon listens.
 
 #[cfg(unix)]
-pub const SOCKET_PATH: &str = "/tmp/fswatchd.sock";
+use std::path::{Path, PathBuf};
+
+/// Environment variable overriding the default socket path or pipe name
+pub const SOCKET_ENV: &str = "FSWATCHD_SOCKET";
+
+#[cfg(unix)]
+const SOCKET_NAME: &str = "fswatchd.sock";
+
+/// Per-user directory holding the socket when there is no runtime directory
+#[cfg(unix)]
+const FALLBACK_DIR: &str = ".fswatchd";
 
 #[cfg(windows)]
 pub const PIPE_NAME: &str = r"\\.\pipe\fswatchd";
+
+/// `$FSWATCHD_SOCKET` if set and not empty
+fn socket_from_env() -> Option<String> {
+    std::env::var(SOCKET_ENV)
+        .ok()
+        .filter(|path| !path.is_empty())
+}
+
+/// Socket path used when none is given: `$FSWATCHD_SOCKET`, else
+/// `$XDG_RUNTIME_DIR/fswatchd.sock`, else `~/.fswatchd/fswatchd.sock`
+#[cfg(unix)]
+pub fn default_socket_path() -> String {
+    resolve_socket_path(
+        socket_from_env(),
+        std::env::var_os("XDG_RUNTIME_DIR"),
+        dirs::home_dir(),
+    )
+}
+
+/// `default_socket_path` from the given environment
+#[cfg(unix)]
+fn resolve_socket_path(
+    socket: Option<String>,
+    runtime_dir: Option<std::ffi::OsString>,
+    home: Option<PathBuf>,
+) -> String {
+    if let Some(path) = socket {
+        return path;
+    }
+    let dir = runtime_dir
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| home.map(|home| home.join(FALLBACK_DIR)))
+        .unwrap_or_else(std::env::temp_dir);
+    dir.join(SOCKET_NAME).to_string_lossy().to_string()
+}
+
+/// Pipe name used when none is given: `$FSWATCHD_SOCKET`, else the default pipe
+#[cfg(windows)]
+pub fn default_pipe_name() -> String {
+    socket_from_env().unwrap_or_else(|| PIPE_NAME.to_string())
+}
+
+/// Create a directory accessible to the current user alone, or restrict an existing one
+#[cfg(unix)]
+fn private_dir(dir: &Path) -> std::io::Result<()> {
+    use std::fs::{DirBuilder, Permissions};
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+
+    DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+    std::fs::set_permissions(dir, Permissions::from_mode(0o700))
+}
+
+/// Listen on a Unix socket only the current user can connect to. Missing parent
+/// directories are created accessible to the user alone, and `~/.fswatchd` is
+/// restricted even if it already exists.
+///
+/// The socket is bound under a temporary name and renamed once its mode is set, so
+/// it never appears at `path` with looser permissions.
+#[cfg(unix)]
+pub fn bind(path: &str) -> std::io::Result<tokio::net::UnixListener> {
+    use std::fs::{DirBuilder, Permissions};
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+
+    if let Some(parent) = Path::new(path).parent()
+        && !parent.as_os_str().is_empty()
+    {
+        let fallback = dirs::home_dir().map(|home| home.join(FALLBACK_DIR));
+        if fallback.is_some_and(|dir| dir == parent) {
+            private_dir(parent)?;
+        } else {
+            DirBuilder::new()
+                .recursive(true)
+                .mode(0o700)
+                .create(parent)?;
+        }
+    }
+
+    let staging = format!("{}.{}", path, std::process::id());
+    let _ = std::fs::remove_file(&staging);
+    let listener = tokio::net::UnixListener::bind(&staging)?;
+    let published = std::fs::set_permissions(&staging, Permissions::from_mode(0o600))
+        .and_then(|()| std::fs::rename(&staging, path));
+    if let Err(e) = published {
+        let _ = std::fs::remove_file(&staging);
+        return Err(e);
+    }
+    Ok(listener)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn mode(path: &Path) -> u32 {
+        std::fs::metadata(path).unwrap().permissions().mode() & 0o777
+    }
+
+    #[test]
+    fn test_socket_path_resolution_order() {
+        let home = Some(PathBuf::from("/home/u"));
+        let runtime = Some(std::ffi::OsString::from("/run/user/1"));
+
+        assert_eq!(
+            resolve_socket_path(
+                Some("/tmp/x.sock".to_string()),
+                runtime.clone(),
+                home.clone()
+            ),
+            "/tmp/x.sock"
+        );
+        assert_eq!(
+            resolve_socket_path(None, runtime, home.clone()),
+            "/run/user/1/fswatchd.sock"
+        );
+        assert_eq!(
+            resolve_socket_path(None, Some(std::ffi::OsString::new()), home.clone()),
+            "/home/u/.fswatchd/fswatchd.sock"
+        );
+        assert_eq!(
+            resolve_socket_path(None, None, home),
+            "/home/u/.fswatchd/fswatchd.sock"
+        );
+    }
+
+    #[test]
+    fn test_private_dir_restricts_existing_dir() {
+        let dir = std::env::temp_dir().join("fswatchd-test-private-dir");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        private_dir(&dir).unwrap();
+        assert_eq!(mode(&dir), 0o700);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_bind_creates_private_socket() {
+        let dir = std::env::temp_dir().join("fswatchd-test-bind");
+        let _ = std::fs::remove_dir_all(&dir);
+        let path = dir.join("run/fswatchd.sock");
+
+        let _listener = bind(path.to_str().unwrap()).unwrap();
+        assert_eq!(mode(&path), 0o600);
+        assert_eq!(mode(path.parent().unwrap()), 0o700);
+        let staged = format!("{}.{}", path.display(), std::process::id());
+        assert!(!Path::new(&staged).exists());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}